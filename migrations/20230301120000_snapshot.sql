@@ -0,0 +1,38 @@
+CREATE TABLE orderbook_event_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    ts TIMESTAMP NOT NULL,
+    event_type TEXT NOT NULL CHECK(event_type IN ('buy', 'sell', 'fill', 'cancel')),
+    order_id TEXT NOT NULL,
+    order_quantity INTEGER,
+    order_price NUMERIC,
+    counterpart_id TEXT,
+    counterpart_quantity INTEGER,
+    counterpart_price NUMERIC
+);
+
+INSERT INTO orderbook_event_new
+    (ts, event_type, order_id, order_quantity, order_price, counterpart_id, counterpart_quantity, counterpart_price)
+SELECT ts, event_type, order_id, order_quantity, order_price, counterpart_id, counterpart_quantity, counterpart_price
+FROM orderbook_event
+ORDER BY rowid;
+
+DROP TABLE orderbook_event;
+ALTER TABLE orderbook_event_new RENAME TO orderbook_event;
+CREATE INDEX idx_orderbook_event_ts ON orderbook_event (ts);
+
+CREATE TABLE orderbook_snapshot (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    ts TIMESTAMP NOT NULL,
+    last_event_id INTEGER NOT NULL
+);
+
+CREATE TABLE orderbook_snapshot_order (
+    snapshot_id INTEGER NOT NULL REFERENCES orderbook_snapshot (id),
+    order_type TEXT NOT NULL CHECK(order_type IN ('buy', 'sell')),
+    order_id TEXT NOT NULL,
+    order_ts TIMESTAMP NOT NULL,
+    order_quantity INTEGER NOT NULL,
+    order_price NUMERIC NOT NULL
+);
+
+CREATE INDEX idx_orderbook_snapshot_order_snapshot_id ON orderbook_snapshot_order (snapshot_id);
@@ -0,0 +1 @@
+ALTER TABLE orderbook_event ADD COLUMN order_kind TEXT CHECK(order_kind IN ('limit', 'market'));
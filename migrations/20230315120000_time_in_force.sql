@@ -0,0 +1,26 @@
+CREATE TABLE orderbook_event_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    ts TIMESTAMP NOT NULL,
+    event_type TEXT NOT NULL CHECK(event_type IN ('buy', 'sell', 'fill', 'cancel', 'expire')),
+    order_id TEXT NOT NULL,
+    order_quantity INTEGER,
+    order_price NUMERIC,
+    counterpart_id TEXT,
+    counterpart_quantity INTEGER,
+    counterpart_price NUMERIC,
+    order_kind TEXT CHECK(order_kind IN ('limit', 'market')),
+    time_in_force TEXT CHECK(time_in_force IN ('gtc', 'ioc', 'fok', 'day'))
+);
+
+INSERT INTO orderbook_event_new
+    (id, ts, event_type, order_id, order_quantity, order_price, counterpart_id, counterpart_quantity, counterpart_price, order_kind, time_in_force)
+SELECT id, ts, event_type, order_id, order_quantity, order_price, counterpart_id, counterpart_quantity, counterpart_price, order_kind,
+    CASE WHEN event_type IN ('buy', 'sell') THEN 'gtc' END
+FROM orderbook_event
+ORDER BY id;
+
+DROP TABLE orderbook_event;
+ALTER TABLE orderbook_event_new RENAME TO orderbook_event;
+CREATE INDEX idx_orderbook_event_ts ON orderbook_event (ts);
+
+ALTER TABLE orderbook_snapshot_order ADD COLUMN time_in_force TEXT NOT NULL DEFAULT 'gtc' CHECK(time_in_force IN ('gtc', 'ioc', 'fok', 'day'));
@@ -0,0 +1,2 @@
+ALTER TABLE orderbook_event ADD COLUMN post_only TEXT CHECK(post_only IN ('reject', 'reprice'));
+ALTER TABLE orderbook_snapshot_order ADD COLUMN post_only TEXT CHECK(post_only IN ('reject', 'reprice'));
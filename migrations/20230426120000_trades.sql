@@ -0,0 +1,27 @@
+CREATE TABLE orderbook_trade (
+    id TEXT PRIMARY KEY,
+    event_id INTEGER NOT NULL,
+    ts TIMESTAMP NOT NULL,
+    price NUMERIC NOT NULL,
+    quantity INTEGER NOT NULL,
+    maker_order_id TEXT NOT NULL,
+    taker_order_id TEXT NOT NULL,
+    aggressor_side TEXT NOT NULL CHECK(aggressor_side IN ('buy', 'sell')),
+    maker_remaining_quantity INTEGER NOT NULL,
+    taker_remaining_quantity INTEGER NOT NULL
+);
+
+CREATE UNIQUE INDEX idx_orderbook_trade_event_id ON orderbook_trade (event_id);
+CREATE INDEX idx_orderbook_trade_ts ON orderbook_trade (ts);
+
+-- Fills saved before trades were recorded get a trade derived from them
+INSERT INTO orderbook_trade
+    (id, event_id, ts, price, quantity, maker_order_id, taker_order_id, aggressor_side, maker_remaining_quantity, taker_remaining_quantity)
+SELECT randomblob(16), fill.id, fill.ts, fill.counterpart_price, MIN(fill.order_quantity, fill.counterpart_quantity),
+    fill.counterpart_id, fill.order_id,
+    COALESCE((SELECT accept.event_type FROM orderbook_event accept
+        WHERE accept.order_id = fill.order_id AND accept.event_type IN ('buy', 'sell') LIMIT 1), 'buy'),
+    fill.counterpart_quantity - MIN(fill.order_quantity, fill.counterpart_quantity),
+    fill.order_quantity - MIN(fill.order_quantity, fill.counterpart_quantity)
+FROM orderbook_event fill
+WHERE fill.event_type = 'fill';
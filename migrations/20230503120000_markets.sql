@@ -0,0 +1,9 @@
+-- Every market has its own events, trades and snapshots, the ones saved
+-- before belong to the only market there was
+ALTER TABLE orderbook_event ADD COLUMN ticker TEXT NOT NULL DEFAULT 'vibranium';
+ALTER TABLE orderbook_trade ADD COLUMN ticker TEXT NOT NULL DEFAULT 'vibranium';
+ALTER TABLE orderbook_snapshot ADD COLUMN ticker TEXT NOT NULL DEFAULT 'vibranium';
+
+CREATE INDEX idx_orderbook_event_ticker_id ON orderbook_event (ticker, id);
+CREATE INDEX idx_orderbook_trade_ticker_ts ON orderbook_trade (ticker, ts);
+CREATE INDEX idx_orderbook_snapshot_ticker ON orderbook_snapshot (ticker);
@@ -0,0 +1,10 @@
+-- Markets listed at runtime, a delisted market keeps its definition
+CREATE TABLE orderbook_market (
+    ticker TEXT PRIMARY KEY,
+    tick_size NUMERIC NOT NULL,
+    lot_size INTEGER NOT NULL,
+    min_price NUMERIC,
+    max_price NUMERIC,
+    listed_ts TIMESTAMP NOT NULL,
+    delisted_ts TIMESTAMP
+);
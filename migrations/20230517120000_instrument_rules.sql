@@ -0,0 +1,5 @@
+-- Add the instrument rules of the markets besides tick and lot size
+ALTER TABLE orderbook_market ADD COLUMN min_quantity INTEGER;
+ALTER TABLE orderbook_market ADD COLUMN max_quantity INTEGER;
+ALTER TABLE orderbook_market ADD COLUMN min_notional NUMERIC;
+ALTER TABLE orderbook_market ADD COLUMN price_band NUMERIC;
@@ -0,0 +1,121 @@
+-- Prices are stored as decimal text, NUMERIC columns kept them as floating
+-- point numbers losing precision
+CREATE TABLE orderbook_event_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    ts TIMESTAMP NOT NULL,
+    event_type TEXT NOT NULL CHECK(event_type IN (
+        'buy', 'sell', 'fill', 'cancel', 'expire', 'trigger', 'replenish', 'exhaust', 'amend',
+        'self_trade_cancel_newest', 'self_trade_cancel_oldest', 'self_trade_cancel_both', 'self_trade_decrement'
+    )),
+    order_id TEXT NOT NULL,
+    order_ts TIMESTAMP,
+    order_quantity INTEGER,
+    order_price TEXT,
+    counterpart_id TEXT,
+    counterpart_quantity INTEGER,
+    counterpart_price TEXT,
+    order_kind TEXT CHECK(order_kind IN ('limit', 'market')),
+    time_in_force TEXT CHECK(time_in_force IN ('gtc', 'ioc', 'fok', 'day')),
+    trigger_price TEXT,
+    display_quantity INTEGER,
+    reserve_quantity INTEGER,
+    post_only TEXT CHECK(post_only IN ('reject', 'reprice')),
+    owner TEXT,
+    ticker TEXT NOT NULL DEFAULT 'vibranium'
+);
+
+INSERT INTO orderbook_event_new
+    (id, ts, event_type, order_id, order_ts, order_quantity, order_price, counterpart_id, counterpart_quantity, counterpart_price, order_kind, time_in_force,
+    trigger_price, display_quantity, reserve_quantity, post_only, owner, ticker)
+SELECT id, ts, event_type, order_id, order_ts, order_quantity, CAST(order_price AS TEXT), counterpart_id, counterpart_quantity, CAST(counterpart_price AS TEXT),
+    order_kind, time_in_force, CAST(trigger_price AS TEXT), display_quantity, reserve_quantity, post_only, owner, ticker
+FROM orderbook_event
+ORDER BY id;
+
+DROP TABLE orderbook_event;
+ALTER TABLE orderbook_event_new RENAME TO orderbook_event;
+CREATE INDEX idx_orderbook_event_ts ON orderbook_event (ts);
+CREATE INDEX idx_orderbook_event_ticker_id ON orderbook_event (ticker, id);
+
+CREATE TABLE orderbook_trade_new (
+    id TEXT PRIMARY KEY,
+    event_id INTEGER NOT NULL,
+    ts TIMESTAMP NOT NULL,
+    price TEXT NOT NULL,
+    quantity INTEGER NOT NULL,
+    maker_order_id TEXT NOT NULL,
+    taker_order_id TEXT NOT NULL,
+    aggressor_side TEXT NOT NULL CHECK(aggressor_side IN ('buy', 'sell')),
+    maker_remaining_quantity INTEGER NOT NULL,
+    taker_remaining_quantity INTEGER NOT NULL,
+    ticker TEXT NOT NULL DEFAULT 'vibranium'
+);
+
+INSERT INTO orderbook_trade_new
+    (id, event_id, ts, price, quantity, maker_order_id, taker_order_id, aggressor_side, maker_remaining_quantity, taker_remaining_quantity, ticker)
+SELECT id, event_id, ts, CAST(price AS TEXT), quantity, maker_order_id, taker_order_id, aggressor_side, maker_remaining_quantity, taker_remaining_quantity, ticker
+FROM orderbook_trade;
+
+DROP TABLE orderbook_trade;
+ALTER TABLE orderbook_trade_new RENAME TO orderbook_trade;
+CREATE UNIQUE INDEX idx_orderbook_trade_event_id ON orderbook_trade (event_id);
+CREATE INDEX idx_orderbook_trade_ts ON orderbook_trade (ts);
+CREATE INDEX idx_orderbook_trade_ticker_ts ON orderbook_trade (ticker, ts);
+
+-- The snapshot orders reference the snapshots, rebuilding the snapshot table
+-- would drop them, its only price column is swapped instead
+ALTER TABLE orderbook_snapshot ADD COLUMN last_price_text TEXT;
+UPDATE orderbook_snapshot SET last_price_text = CAST(last_price AS TEXT);
+ALTER TABLE orderbook_snapshot DROP COLUMN last_price;
+ALTER TABLE orderbook_snapshot RENAME COLUMN last_price_text TO last_price;
+
+CREATE TABLE orderbook_snapshot_order_new (
+    snapshot_id INTEGER NOT NULL REFERENCES orderbook_snapshot (id),
+    order_type TEXT NOT NULL CHECK(order_type IN ('buy', 'sell')),
+    order_id TEXT NOT NULL,
+    order_ts TIMESTAMP NOT NULL,
+    order_quantity INTEGER NOT NULL,
+    order_price TEXT NOT NULL,
+    time_in_force TEXT NOT NULL DEFAULT 'gtc' CHECK(time_in_force IN ('gtc', 'ioc', 'fok', 'day')),
+    order_kind TEXT NOT NULL DEFAULT 'limit' CHECK(order_kind IN ('limit', 'market')),
+    trigger_price TEXT,
+    display_quantity INTEGER,
+    reserve_quantity INTEGER NOT NULL DEFAULT 0,
+    post_only TEXT CHECK(post_only IN ('reject', 'reprice')),
+    owner TEXT
+);
+
+INSERT INTO orderbook_snapshot_order_new
+    (snapshot_id, order_type, order_id, order_ts, order_quantity, order_price, time_in_force, order_kind, trigger_price, display_quantity, reserve_quantity,
+    post_only, owner)
+SELECT snapshot_id, order_type, order_id, order_ts, order_quantity, CAST(order_price AS TEXT), time_in_force, order_kind, CAST(trigger_price AS TEXT),
+    display_quantity, reserve_quantity, post_only, owner
+FROM orderbook_snapshot_order
+ORDER BY rowid;
+
+DROP TABLE orderbook_snapshot_order;
+ALTER TABLE orderbook_snapshot_order_new RENAME TO orderbook_snapshot_order;
+CREATE INDEX idx_orderbook_snapshot_order_snapshot_id ON orderbook_snapshot_order (snapshot_id);
+
+CREATE TABLE orderbook_market_new (
+    ticker TEXT PRIMARY KEY,
+    tick_size TEXT NOT NULL,
+    lot_size INTEGER NOT NULL,
+    min_price TEXT,
+    max_price TEXT,
+    listed_ts TIMESTAMP NOT NULL,
+    delisted_ts TIMESTAMP,
+    min_quantity INTEGER,
+    max_quantity INTEGER,
+    min_notional TEXT,
+    price_band TEXT
+);
+
+INSERT INTO orderbook_market_new
+    (ticker, tick_size, lot_size, min_price, max_price, listed_ts, delisted_ts, min_quantity, max_quantity, min_notional, price_band)
+SELECT ticker, CAST(tick_size AS TEXT), lot_size, CAST(min_price AS TEXT), CAST(max_price AS TEXT), listed_ts, delisted_ts, min_quantity, max_quantity,
+    CAST(min_notional AS TEXT), CAST(price_band AS TEXT)
+FROM orderbook_market;
+
+DROP TABLE orderbook_market;
+ALTER TABLE orderbook_market_new RENAME TO orderbook_market;
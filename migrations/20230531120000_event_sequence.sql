@@ -0,0 +1,40 @@
+-- Every event of a market is numbered, without gaps, in the order it was
+-- saved, the events saved before are numbered by id
+CREATE TABLE orderbook_event_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    ts TIMESTAMP NOT NULL,
+    event_type TEXT NOT NULL CHECK(event_type IN (
+        'buy', 'sell', 'fill', 'cancel', 'expire', 'trigger', 'replenish', 'exhaust', 'amend',
+        'self_trade_cancel_newest', 'self_trade_cancel_oldest', 'self_trade_cancel_both', 'self_trade_decrement'
+    )),
+    order_id TEXT NOT NULL,
+    order_ts TIMESTAMP,
+    order_quantity INTEGER,
+    order_price TEXT,
+    counterpart_id TEXT,
+    counterpart_quantity INTEGER,
+    counterpart_price TEXT,
+    order_kind TEXT CHECK(order_kind IN ('limit', 'market')),
+    time_in_force TEXT CHECK(time_in_force IN ('gtc', 'ioc', 'fok', 'day')),
+    trigger_price TEXT,
+    display_quantity INTEGER,
+    reserve_quantity INTEGER,
+    post_only TEXT CHECK(post_only IN ('reject', 'reprice')),
+    owner TEXT,
+    ticker TEXT NOT NULL DEFAULT 'vibranium',
+    sequence INTEGER NOT NULL,
+    UNIQUE (ticker, sequence)
+);
+
+INSERT INTO orderbook_event_new
+    (id, ts, event_type, order_id, order_ts, order_quantity, order_price, counterpart_id, counterpart_quantity, counterpart_price, order_kind, time_in_force,
+    trigger_price, display_quantity, reserve_quantity, post_only, owner, ticker, sequence)
+SELECT id, ts, event_type, order_id, order_ts, order_quantity, order_price, counterpart_id, counterpart_quantity, counterpart_price, order_kind, time_in_force,
+    trigger_price, display_quantity, reserve_quantity, post_only, owner, ticker, ROW_NUMBER() OVER (PARTITION BY ticker ORDER BY id)
+FROM orderbook_event
+ORDER BY id;
+
+DROP TABLE orderbook_event;
+ALTER TABLE orderbook_event_new RENAME TO orderbook_event;
+CREATE INDEX idx_orderbook_event_ts ON orderbook_event (ts);
+CREATE INDEX idx_orderbook_event_ticker_id ON orderbook_event (ticker, id);
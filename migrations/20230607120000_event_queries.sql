@@ -0,0 +1,4 @@
+-- Historical queries filter the events of a market by time and by order
+CREATE INDEX idx_orderbook_event_ticker_ts ON orderbook_event (ticker, ts);
+CREATE INDEX idx_orderbook_event_order_id ON orderbook_event (order_id);
+CREATE INDEX idx_orderbook_event_counterpart_id ON orderbook_event (counterpart_id);
@@ -1,10 +1,24 @@
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+
+use chrono::{DateTime, NaiveTime, Utc};
 use rust_decimal::Decimal;
-use tokio::sync::{mpsc, oneshot};
+use serde::Serialize;
+use tokio::{
+    sync::{broadcast, mpsc, oneshot},
+    time::{Instant, Interval},
+};
 use uuid::Uuid;
 
-use crate::order_book::{Command, Event, OrderBook, OrderBookState};
+use crate::order_book::{
+    Command, Event, Order, OrderBook, OrderBookDepth, OrderBookState, OrderKind, OrderType,
+    PostOnly, PriceLevel, SelfTradePrevention, TimeInForce, Trade,
+};
 
-use crate::{database, Error, Result};
+use crate::{
+    database::{self, LoggedEvent},
+    registry::Market,
+    Error, Result,
+};
 
 #[derive(Clone)]
 pub struct Client {
@@ -16,15 +30,20 @@ impl Client {
         Self { sender: tx }
     }
 
-    async fn call(&self, command: Command) -> Result<Vec<Event>> {
+    async fn send(&self, request: Request) -> Result<()> {
+        self.sender.send(request).await.map_err(|error| {
+            tracing::error!("Fail to send request to actor, error={}", error);
+            Error::application_error("Internal server error")
+        })
+    }
+
+    async fn call(&self, command: Command) -> Result<Vec<SequencedEvent>> {
         let (sender, receiver) = oneshot::channel();
-        self.sender
-            .send(Request::new(command, sender))
-            .await
-            .map_err(|error| {
-                tracing::error!("Fail to send command to actor, error={}", error);
-                Error::application_error("Internal server error")
-            })?;
+        self.send(Request::Command {
+            command,
+            callback: sender,
+        })
+        .await?;
         let events = receiver.await.map_err(|error| {
             tracing::warn!(
                 "Fail to send back the response for the caller, dropping response, error={}",
@@ -35,29 +54,203 @@ impl Client {
         Ok(events)
     }
 
+    pub async fn snapshot(&self) -> Result<SnapshotInfo> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(Request::Snapshot { callback: sender }).await?;
+        receiver.await.map_err(|error| {
+            tracing::warn!("Fail to receive the snapshot response, error={}", error);
+            Error::application_error("Internal server error")
+        })?
+    }
+
     pub async fn get_order_book(&self) -> Result<OrderBookState> {
         let mut events = self.call(Command::GetState).await?;
-        match (events.len(), events.pop()) {
-            (1, Some(Event::State { state })) => Ok(state),
+        match events.pop().map(|event| event.event) {
+            Some(Event::State { state }) => Ok(state),
+            _ => Err(Error::application_error("Internal server error")),
+        }
+    }
+
+    pub async fn ticker(&self) -> Result<Ticker> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(Request::Ticker { callback: sender }).await?;
+        receiver.await.map_err(|error| {
+            tracing::warn!("Fail to receive the ticker response, error={}", error);
+            Error::application_error("Internal server error")
+        })
+    }
+
+    /// Subscribes to the market data published after each command, starting
+    /// from a snapshot consistent with the first update received.
+    pub async fn subscribe(&self, channels: Channels) -> Result<Subscription> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(Request::Subscribe {
+            channels,
+            callback: sender,
+        })
+        .await?;
+        receiver.await.map_err(|error| {
+            tracing::warn!("Fail to receive the subscription, error={}", error);
+            Error::application_error("Internal server error")
+        })
+    }
+
+    /// Cancels every order of the market then stops its actor, returning the
+    /// events of the cancellations.
+    pub async fn delist(&self) -> Result<Vec<SequencedEvent>> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(Request::Delist { callback: sender }).await?;
+        receiver.await.map_err(|error| {
+            tracing::warn!("Fail to receive the delisting response, error={}", error);
+            Error::application_error("Internal server error")
+        })
+    }
+
+    pub async fn tail(&self) -> Result<EventLogTail> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(Request::Tail { callback: sender }).await?;
+        receiver.await.map_err(|error| {
+            tracing::warn!("Fail to receive the event log tail, error={}", error);
+            Error::application_error("Internal server error")
+        })
+    }
+
+    pub async fn get_depth(&self, levels: usize) -> Result<OrderBookDepth> {
+        let mut events = self.call(Command::GetDepth { levels }).await?;
+        match events.pop().map(|event| event.event) {
+            Some(Event::Depth { depth }) => Ok(depth),
             _ => Err(Error::application_error("Internal server error")),
         }
     }
 
-    pub async fn buy(&self, quantity: u32, price: Decimal) -> Result<Vec<Event>> {
-        self.call(Command::Buy { quantity, price }).await
+    pub async fn buy(
+        &self,
+        quantity: u32,
+        price: Decimal,
+        time_in_force: TimeInForce,
+        post_only: Option<PostOnly>,
+        owner: Option<String>,
+    ) -> Result<Vec<SequencedEvent>> {
+        self.call(Command::Buy {
+            quantity,
+            price,
+            time_in_force,
+            post_only,
+            owner,
+        })
+        .await
+    }
+
+    pub async fn sell(
+        &self,
+        quantity: u32,
+        price: Decimal,
+        time_in_force: TimeInForce,
+        post_only: Option<PostOnly>,
+        owner: Option<String>,
+    ) -> Result<Vec<SequencedEvent>> {
+        self.call(Command::Sell {
+            quantity,
+            price,
+            time_in_force,
+            post_only,
+            owner,
+        })
+        .await
+    }
+
+    pub async fn market_buy(&self, quantity: u32) -> Result<Vec<SequencedEvent>> {
+        self.call(Command::MarketBuy { quantity }).await
+    }
+
+    pub async fn market_sell(&self, quantity: u32) -> Result<Vec<SequencedEvent>> {
+        self.call(Command::MarketSell { quantity }).await
+    }
+
+    pub async fn buy_stop(
+        &self,
+        quantity: u32,
+        trigger_price: Decimal,
+        limit_price: Option<Decimal>,
+        time_in_force: TimeInForce,
+    ) -> Result<Vec<SequencedEvent>> {
+        self.call(Command::BuyStop {
+            quantity,
+            trigger_price,
+            limit_price,
+            time_in_force,
+        })
+        .await
+    }
+
+    pub async fn sell_stop(
+        &self,
+        quantity: u32,
+        trigger_price: Decimal,
+        limit_price: Option<Decimal>,
+        time_in_force: TimeInForce,
+    ) -> Result<Vec<SequencedEvent>> {
+        self.call(Command::SellStop {
+            quantity,
+            trigger_price,
+            limit_price,
+            time_in_force,
+        })
+        .await
     }
 
-    pub async fn sell(&self, quantity: u32, price: Decimal) -> Result<Vec<Event>> {
-        self.call(Command::Sell { quantity, price }).await
+    pub async fn buy_iceberg(
+        &self,
+        quantity: u32,
+        display_quantity: u32,
+        price: Decimal,
+        time_in_force: TimeInForce,
+    ) -> Result<Vec<SequencedEvent>> {
+        self.call(Command::BuyIceberg {
+            quantity,
+            display_quantity,
+            price,
+            time_in_force,
+        })
+        .await
     }
 
-    pub async fn cancel(&self, order: Uuid) -> Result<Vec<Event>> {
-        self.call(Command::Cancel { id: order }).await
+    pub async fn sell_iceberg(
+        &self,
+        quantity: u32,
+        display_quantity: u32,
+        price: Decimal,
+        time_in_force: TimeInForce,
+    ) -> Result<Vec<SequencedEvent>> {
+        self.call(Command::SellIceberg {
+            quantity,
+            display_quantity,
+            price,
+            time_in_force,
+        })
+        .await
     }
 
-    pub async fn update(&self, order: Uuid, quantity: u32, price: Decimal) -> Result<Vec<Event>> {
+    /// Cancels an order, of the given side when there is one.
+    pub async fn cancel(
+        &self,
+        order: Uuid,
+        side: Option<OrderType>,
+    ) -> Result<Vec<SequencedEvent>> {
+        self.call(Command::Cancel { id: order, side }).await
+    }
+
+    /// Amends an order, of the given side when there is one.
+    pub async fn update(
+        &self,
+        order: Uuid,
+        side: Option<OrderType>,
+        quantity: u32,
+        price: Decimal,
+    ) -> Result<Vec<SequencedEvent>> {
         self.call(Command::Update {
             id: order,
+            side,
             new_quantity: quantity,
             new_price: price,
         })
@@ -66,56 +259,910 @@ impl Client {
 }
 
 #[derive(Debug)]
-pub struct Request {
-    command: Command,
-    callback: oneshot::Sender<Vec<Event>>,
+pub enum Request {
+    Command {
+        command: Command,
+        callback: oneshot::Sender<Vec<SequencedEvent>>,
+    },
+    Snapshot {
+        callback: oneshot::Sender<Result<SnapshotInfo>>,
+    },
+    Ticker {
+        callback: oneshot::Sender<Ticker>,
+    },
+    Subscribe {
+        channels: Channels,
+        callback: oneshot::Sender<Subscription>,
+    },
+    Tail {
+        callback: oneshot::Sender<EventLogTail>,
+    },
+    /// Cancels every order then stops the actor.
+    Delist {
+        callback: oneshot::Sender<Vec<SequencedEvent>>,
+    },
+}
+
+/// An event emitted by a command, numbered by its position among the events
+/// of the market once saved in the event log.
+#[derive(Debug, Clone, Serialize)]
+pub struct SequencedEvent {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SnapshotInfo {
+    pub ts: DateTime<Utc>,
+    pub last_event_id: i64,
+}
+
+/// Top of the book and trade summary, the volume is the quantity traded in
+/// the last 24 hours.
+#[derive(Debug, Clone, Serialize)]
+pub struct Ticker {
+    pub ts: DateTime<Utc>,
+    pub best_bid: Option<Decimal>,
+    pub best_ask: Option<Decimal>,
+    pub spread: Option<Decimal>,
+    pub mid_price: Option<Decimal>,
+    pub last_price: Option<Decimal>,
+    pub last_quantity: Option<u32>,
+    pub volume_24h: u64,
+}
+
+/// The kinds of market data a subscriber is interested in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Channels {
+    pub trades: bool,
+    pub depth: bool,
+    pub orders: bool,
+}
+
+impl Default for Channels {
+    fn default() -> Self {
+        Self {
+            trades: true,
+            depth: true,
+            orders: true,
+        }
+    }
+}
+
+/// The market data published once the events of a command are persisted.
+/// Updates are numbered with consecutive sequence numbers, a subscriber
+/// missing one must subscribe again. After a restart the numbers jump ahead
+/// of those before it.
+#[derive(Debug, Clone, Serialize)]
+pub struct MarketDataUpdate {
+    pub ticker: String,
+    pub sequence: u64,
+    pub ts: DateTime<Utc>,
+    pub trades: Vec<Trade>,
+    /// The new state of every price level changed by the command, a level
+    /// without orders was removed from the book.
+    pub depth: Vec<DepthUpdate>,
+    /// The events of the command changing orders, as numbered in the event
+    /// log and with only what everyone may see of the orders.
+    pub orders: Vec<SequencedEvent>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DepthUpdate {
+    pub side: OrderType,
+    #[serde(flatten)]
+    pub level: PriceLevel,
+}
+
+/// The state of the order book right after the update numbered `sequence`,
+/// with only the subscribed channels.
+#[derive(Debug, Clone, Serialize)]
+pub struct MarketDataSnapshot {
+    pub ticker: String,
+    pub sequence: u64,
+    pub ts: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub depth: Option<OrderBookDepth>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub orders: Option<OrderBookState>,
+}
+
+#[derive(Debug)]
+pub struct Subscription {
+    pub snapshot: MarketDataSnapshot,
+    pub updates: broadcast::Receiver<Arc<MarketDataUpdate>>,
 }
 
-impl Request {
-    fn new(command: Command, callback: oneshot::Sender<Vec<Event>>) -> Self {
-        Self { command, callback }
+/// The events saved in the event log after the one numbered `last_sequence`,
+/// as they are persisted.
+#[derive(Debug)]
+pub struct EventLogTail {
+    pub last_sequence: u64,
+    pub events: broadcast::Receiver<LoggedEvent>,
+}
+
+/// Running statistics of the persisted trades.
+#[derive(Debug, Default)]
+struct TradeStatistics {
+    last: Option<(Decimal, u32)>,
+    window: VecDeque<(DateTime<Utc>, u32)>,
+    volume: u64,
+}
+
+impl TradeStatistics {
+    fn window() -> chrono::Duration {
+        chrono::Duration::hours(24)
+    }
+
+    fn record(&mut self, trade: &Trade) {
+        self.last = Some((trade.price, trade.quantity));
+        self.window.push_back((trade.ts, trade.quantity));
+        self.volume += trade.quantity as u64;
+        self.volume(trade.ts);
     }
+
+    /// The quantity traded in the window ending at `ts`, forgetting the trades
+    /// already out of it.
+    fn volume(&mut self, ts: DateTime<Utc>) -> u64 {
+        while let Some((trade_ts, quantity)) = self.window.front() {
+            if *trade_ts > ts - TradeStatistics::window() {
+                break;
+            }
+            self.volume -= *quantity as u64;
+            self.window.pop_front();
+        }
+        self.volume
+    }
+}
+
+/// How many updates, or logged events, a subscriber can fall behind before
+/// missing some.
+const UPDATES_BUFFER: usize = 1024;
+
+/// When the actor takes a snapshot of the order book: after a number of
+/// persisted events of its market since the last snapshot and/or every
+/// period of time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SnapshotPolicy {
+    pub every_events: Option<u64>,
+    pub every: Option<Duration>,
 }
 
 pub struct Actor {
     receiver: mpsc::Receiver<Request>,
     order_book: OrderBook,
     db: sqlx::Pool<sqlx::Sqlite>,
+    snapshot_policy: SnapshotPolicy,
+    last_event_id: i64,
+    last_sequence: u64,
+    snapshot_sequence: u64,
+    trade_statistics: TradeStatistics,
+    updates: broadcast::Sender<Arc<MarketDataUpdate>>,
+    /// The number of the last update published. It starts from the last
+    /// persisted event so updates keep increasing across restarts, each
+    /// update persisting at least one event.
+    sequence: u64,
+    event_log: broadcast::Sender<LoggedEvent>,
 }
 
 impl Actor {
-    fn new(db: sqlx::Pool<sqlx::Sqlite>, receiver: mpsc::Receiver<Request>, ticker: &str) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        db: sqlx::Pool<sqlx::Sqlite>,
+        receiver: mpsc::Receiver<Request>,
+        order_book: OrderBook,
+        snapshot_policy: SnapshotPolicy,
+        last_event_id: i64,
+        last_sequence: u64,
+        snapshot_sequence: u64,
+        trade_statistics: TradeStatistics,
+    ) -> Self {
         Self {
             db,
             receiver,
-            order_book: OrderBook::new(ticker),
+            order_book,
+            snapshot_policy,
+            last_event_id,
+            last_sequence,
+            snapshot_sequence,
+            trade_statistics,
+            updates: broadcast::channel(UPDATES_BUFFER).0,
+            sequence: last_sequence,
+            event_log: broadcast::channel(UPDATES_BUFFER).0,
         }
     }
 
     pub async fn run(mut self) -> Result<()> {
         tracing::info!("Waiting for commands");
-        while let Some(request) = self.receiver.recv().await {
-            let events = self.order_book.process(request.command);
-            match database::save_events(&self.db, &events).await {
-                Ok(_) => match request.callback.send(events) {
-                    Ok(_) => (),
-                    Err(events) => {
-                        tracing::error!("Sender dropped the message, events dropped={:?}", events);
+        let mut snapshot_interval = self
+            .snapshot_policy
+            .every
+            .map(|every| tokio::time::interval_at(Instant::now() + every, every));
+        loop {
+            tokio::select! {
+                request = self.receiver.recv() => match request {
+                    Some(Request::Delist { callback }) => {
+                        let command = Command::CancelAll;
+                        self.handle(Request::Command { command, callback }).await;
+                        break;
                     }
+                    Some(request) => self.handle(request).await,
+                    None => break,
                 },
+                _ = tick(&mut snapshot_interval) => {
+                    if self.last_sequence > self.snapshot_sequence {
+                        let _ = self.snapshot().await;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle(&mut self, request: Request) {
+        match request {
+            Request::Command { command, callback } => {
+                let amended = match &command {
+                    Command::Update { id, .. } => self.order_book.order(id).cloned(),
+                    _ => None,
+                };
+                let events = self.order_book.process(command);
+                let events = self.record(events, amended).await;
+                if let Err(events) = callback.send(events) {
+                    tracing::error!("Sender dropped the message, events dropped={:?}", events);
+                }
+            }
+            Request::Snapshot { callback } => {
+                let result = self.snapshot().await;
+                if callback.send(result).is_err() {
+                    tracing::warn!("Sender dropped the snapshot response");
+                }
+            }
+            Request::Ticker { callback } => {
+                self.expire_orders().await;
+                if callback.send(self.ticker()).is_err() {
+                    tracing::warn!("Sender dropped the ticker response");
+                }
+            }
+            Request::Tail { callback } => {
+                let tail = EventLogTail {
+                    last_sequence: self.last_sequence,
+                    events: self.event_log.subscribe(),
+                };
+                if callback.send(tail).is_err() {
+                    tracing::warn!("Sender dropped the event log tail");
+                }
+            }
+            Request::Delist { .. } => unreachable!("Delisting is handled by the run loop"),
+            Request::Subscribe { channels, callback } => {
+                self.expire_orders().await;
+                let subscription = self.subscribe(channels);
+                if callback.send(subscription).is_err() {
+                    tracing::warn!("Sender dropped the subscription");
+                }
+            }
+        }
+    }
+
+    /// Persists the events of a command, or of expiries, then feeds them to the trade
+    /// statistics, the event log tail and the subscribers. `amended` is the
+    /// order an update command changed as it was before.
+    async fn record(&mut self, events: Vec<Event>, amended: Option<Order>) -> Vec<SequencedEvent> {
+        let ticker = &self.order_book.ticker;
+        let logged =
+            match database::save_events(&self.db, ticker, self.last_sequence, &events).await {
+                Ok(logged) => logged,
                 Err(error) => {
                     tracing::error!("Fail to persist events={:?}, error={}", events, error);
                     panic!("Fail to persist events! Error={}", error)
                 }
+            };
+        if let Some(last) = logged.last() {
+            self.last_event_id = last.id;
+            self.last_sequence = last.sequence;
+        }
+        let mut sequences = logged.iter().map(|event| event.sequence);
+        let events: Vec<SequencedEvent> = events
+            .into_iter()
+            .map(|event| SequencedEvent {
+                sequence: database::is_logged(&event)
+                    .then(|| sequences.next())
+                    .flatten(),
+                event,
+            })
+            .collect();
+        for event in logged {
+            // Sending only fails without subscribers left.
+            let _ = self.event_log.send(event);
+        }
+        for event in &events {
+            if let Event::Filled { trade, .. } = &event.event {
+                self.trade_statistics.record(trade);
             }
         }
-        Ok(())
+        self.publish(&events, amended);
+        if let Some(every_events) = self.snapshot_policy.every_events {
+            if self.last_sequence - self.snapshot_sequence >= every_events {
+                let _ = self.snapshot().await;
+            }
+        }
+        events
+    }
+
+    /// Expires the day orders of a session ended since the last command,
+    /// before answering with the book as it is now.
+    async fn expire_orders(&mut self) {
+        let events = self.order_book.expire_orders();
+        if !events.is_empty() {
+            self.record(events, None).await;
+        }
+    }
+
+    fn subscribe(&self, channels: Channels) -> Subscription {
+        let snapshot = MarketDataSnapshot {
+            ticker: self.order_book.ticker.clone(),
+            sequence: self.sequence,
+            ts: Utc::now(),
+            depth: channels.depth.then(|| self.order_book.depth(usize::MAX)),
+            orders: channels.orders.then(|| self.order_book.state().public()),
+        };
+        Subscription {
+            snapshot,
+            updates: self.updates.subscribe(),
+        }
+    }
+
+    /// Publishes the persisted events of a command, `amended` is the order
+    /// an update command changed as it was before.
+    fn publish(&mut self, events: &[SequencedEvent], amended: Option<Order>) {
+        let orders: Vec<SequencedEvent> = events
+            .iter()
+            .filter(|event| event.sequence.is_some())
+            .map(|event| SequencedEvent {
+                sequence: event.sequence,
+                event: event.event.public(),
+            })
+            .collect();
+        if orders.is_empty() {
+            return;
+        }
+        self.sequence += 1;
+        if self.updates.receiver_count() == 0 {
+            return;
+        }
+        let trades = events
+            .iter()
+            .filter_map(|event| match &event.event {
+                Event::Filled { trade, .. } => Some(trade.clone()),
+                _ => None,
+            })
+            .collect();
+        let mut levels: Vec<(OrderType, Decimal)> = Vec::new();
+        for order in orders
+            .iter()
+            .flat_map(|event| event_orders(&event.event))
+            .chain(amended.as_ref())
+            .filter(|order| order.kind == OrderKind::Limit && order.trigger_price.is_none())
+        {
+            if !levels.contains(&(order.order_type, order.price)) {
+                levels.push((order.order_type, order.price));
+            }
+        }
+        let depth = levels
+            .into_iter()
+            .map(|(side, price)| DepthUpdate {
+                side,
+                level: self.order_book.price_level(side, price),
+            })
+            .collect();
+        let update = MarketDataUpdate {
+            ticker: self.order_book.ticker.clone(),
+            sequence: self.sequence,
+            ts: Utc::now(),
+            trades,
+            depth,
+            orders,
+        };
+        // Sending only fails without subscribers left.
+        let _ = self.updates.send(Arc::new(update));
+    }
+
+    fn ticker(&mut self) -> Ticker {
+        let ts = Utc::now();
+        let best_bid = self.order_book.best_bid();
+        let best_ask = self.order_book.best_ask();
+        let (spread, mid_price) = match (best_bid, best_ask) {
+            (Some(bid), Some(ask)) => (Some(ask - bid), Some((ask + bid) / Decimal::TWO)),
+            _ => (None, None),
+        };
+        let last = self.trade_statistics.last;
+        Ticker {
+            ts,
+            best_bid,
+            best_ask,
+            spread,
+            mid_price,
+            last_price: last.map(|(price, _)| price),
+            last_quantity: last.map(|(_, quantity)| quantity),
+            volume_24h: self.trade_statistics.volume(ts),
+        }
+    }
+
+    async fn snapshot(&mut self) -> Result<SnapshotInfo> {
+        let snapshot = database::Snapshot {
+            ts: Utc::now(),
+            last_event_id: self.last_event_id,
+            state: self.order_book.state(),
+        };
+        if let Err(error) =
+            database::save_snapshot(&self.db, &self.order_book.ticker, &snapshot).await
+        {
+            tracing::error!("Fail to save snapshot, error={}", error);
+            return Err(error.into());
+        }
+        tracing::info!("Snapshot saved, last_event_id={}", snapshot.last_event_id);
+        self.snapshot_sequence = self.last_sequence;
+        Ok(SnapshotInfo {
+            ts: snapshot.ts,
+            last_event_id: snapshot.last_event_id,
+        })
+    }
+}
+
+/// The orders an event refers to.
+fn event_orders(event: &Event) -> Vec<&Order> {
+    match event {
+        Event::Filled {
+            order, counterpart, ..
+        }
+        | Event::SelfTradeCanceledNewest {
+            order, counterpart, ..
+        }
+        | Event::SelfTradeCanceledOldest {
+            order, counterpart, ..
+        }
+        | Event::SelfTradeCanceledBoth {
+            order, counterpart, ..
+        }
+        | Event::SelfTradeDecremented {
+            order, counterpart, ..
+        } => vec![order, counterpart],
+        Event::Accepted { order, .. }
+        | Event::Canceled { order, .. }
+        | Event::Expired { order, .. }
+        | Event::Triggered { order, .. }
+        | Event::Replenished { order, .. }
+        | Event::Exhausted { order, .. }
+        | Event::Amended { order, .. } => vec![order],
+        Event::Rejected { .. } | Event::State { .. } | Event::Depth { .. } => vec![],
+    }
+}
+
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
     }
 }
 
-pub fn build(db: sqlx::Pool<sqlx::Sqlite>, ticker: &str, channel_buffer: usize) -> (Client, Actor) {
+pub async fn build(
+    db: sqlx::Pool<sqlx::Sqlite>,
+    market: &Market,
+    channel_buffer: usize,
+    snapshot_policy: SnapshotPolicy,
+    session_end: NaiveTime,
+    self_trade_prevention: SelfTradePrevention,
+    check_invariants: bool,
+) -> Result<(Client, Actor)> {
+    let ticker = &market.ticker;
+    let (snapshot_event_id, state) = match database::load_latest_snapshot(&db, ticker).await? {
+        Some(snapshot) => (snapshot.last_event_id, snapshot.state),
+        None => (0, OrderBookState::default()),
+    };
+    let orders = state
+        .buy
+        .iter()
+        .chain(state.sell.iter())
+        .chain(state.stop.iter())
+        .cloned();
+    let events = database::load_events(&db, ticker, snapshot_event_id, orders).await?;
+    tracing::info!(
+        "Restoring order book from snapshot at event {} and {} events after it",
+        snapshot_event_id,
+        events.len()
+    );
+    let order_book = OrderBook::restore(ticker, state, events)
+        .with_session_end(session_end)
+        .with_tick_size(market.tick_size)
+        .with_rules(market.rules.clone())
+        .with_self_trade_prevention(self_trade_prevention)
+        .with_invariant_checks(check_invariants);
+    let last_event_id = database::last_event_id(&db, ticker).await?;
+    let last_sequence = database::last_sequence(&db, ticker).await?;
+    let snapshot_sequence = database::sequence_at(&db, ticker, snapshot_event_id).await?;
+    let mut trade_statistics = TradeStatistics::default();
+    let trades = database::load_trades(&db, ticker, Utc::now() - TradeStatistics::window()).await?;
+    for trade in &trades {
+        trade_statistics.record(trade);
+    }
+    if trades.is_empty() {
+        trade_statistics.last = database::load_last_trade(&db, ticker)
+            .await?
+            .map(|trade| (trade.price, trade.quantity));
+    }
     let (sender, receiver) = mpsc::channel(channel_buffer);
     let client = Client::new(sender);
-    let server = Actor::new(db, receiver, ticker);
-    (client, server)
+    let server = Actor::new(
+        db,
+        receiver,
+        order_book,
+        snapshot_policy,
+        last_event_id,
+        last_sequence,
+        snapshot_sequence,
+        trade_statistics,
+    );
+    Ok((client, server))
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use sqlx::sqlite::SqlitePoolOptions;
+    use tokio::task::JoinHandle;
+
+    use super::*;
+
+    async fn launch(
+        db: &sqlx::Pool<sqlx::Sqlite>,
+        ticker: &str,
+        snapshot_policy: SnapshotPolicy,
+    ) -> (Client, JoinHandle<Result<()>>) {
+        let (client, actor) = build(
+            db.clone(),
+            &Market::new(ticker, dec!(0.01)),
+            8,
+            snapshot_policy,
+            NaiveTime::MIN,
+            SelfTradePrevention::default(),
+            true,
+        )
+        .await
+        .unwrap();
+        let handle = tokio::task::spawn_local(actor.run());
+        (client, handle)
+    }
+
+    async fn start(db: &sqlx::Pool<sqlx::Sqlite>) -> Client {
+        launch(db, "test", SnapshotPolicy::default()).await.0
+    }
+
+    #[tokio::test]
+    async fn test_ticker_summarizes_book_and_trades() {
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let db = SqlitePoolOptions::new()
+                    .max_connections(1)
+                    .connect("sqlite::memory:")
+                    .await
+                    .unwrap();
+                database::run_migrations(&db).await.unwrap();
+                let client = start(&db).await;
+
+                let ticker = client.ticker().await.unwrap();
+                assert_eq!(ticker.best_bid, None);
+                assert_eq!(ticker.spread, None);
+                assert_eq!(ticker.volume_24h, 0);
+
+                let gtc = TimeInForce::GoodTillCancel;
+                client.buy(5, dec!(2), gtc, None, None).await.unwrap();
+                client.sell(3, dec!(4), gtc, None, None).await.unwrap();
+                client.sell(2, dec!(1), gtc, None, None).await.unwrap();
+                client.sell(1, dec!(2), gtc, None, None).await.unwrap();
+
+                let ticker = client.ticker().await.unwrap();
+                assert_eq!(ticker.best_bid, Some(dec!(2)));
+                assert_eq!(ticker.best_ask, Some(dec!(4)));
+                assert_eq!(ticker.spread, Some(dec!(2)));
+                assert_eq!(ticker.mid_price, Some(dec!(3)));
+                assert_eq!(ticker.last_price, Some(dec!(2)));
+                assert_eq!(ticker.last_quantity, Some(1));
+                assert_eq!(ticker.volume_24h, 3);
+
+                let restarted = start(&db).await.ticker().await.unwrap();
+                assert_eq!(restarted.last_price, ticker.last_price);
+                assert_eq!(restarted.last_quantity, ticker.last_quantity);
+                assert_eq!(restarted.volume_24h, ticker.volume_24h);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_day_orders_expire_before_reads() {
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let db = SqlitePoolOptions::new()
+                    .max_connections(1)
+                    .connect("sqlite::memory:")
+                    .await
+                    .unwrap();
+                database::run_migrations(&db).await.unwrap();
+                let session_end = (Utc::now() + chrono::Duration::milliseconds(300)).time();
+                let (client, actor) = build(
+                    db.clone(),
+                    &Market::new("test", dec!(0.01)),
+                    8,
+                    SnapshotPolicy::default(),
+                    session_end,
+                    SelfTradePrevention::default(),
+                    true,
+                )
+                .await
+                .unwrap();
+                tokio::task::spawn_local(actor.run());
+
+                let day = TimeInForce::Day;
+                let gtc = TimeInForce::GoodTillCancel;
+                client.buy(5, dec!(2), day, None, None).await.unwrap();
+                client.sell(3, dec!(4), gtc, None, None).await.unwrap();
+                let mut tail = client.tail().await.unwrap();
+                tokio::time::sleep(Duration::from_millis(400)).await;
+
+                let ticker = client.ticker().await.unwrap();
+                assert_eq!(ticker.best_bid, None);
+                assert_eq!(ticker.best_ask, Some(dec!(4)));
+                let expired = tail.events.recv().await.unwrap();
+                assert_eq!(
+                    (expired.sequence, expired.event_type.as_str()),
+                    (3, "expire")
+                );
+
+                let subscription = client.subscribe(Channels::default()).await.unwrap();
+                assert_eq!(subscription.snapshot.sequence, 3);
+                let orders = subscription.snapshot.orders.unwrap();
+                assert_eq!((orders.buy.len(), orders.sell.len()), (0, 1));
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_subscription_receives_snapshot_then_sequenced_updates() {
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let db = SqlitePoolOptions::new()
+                    .max_connections(1)
+                    .connect("sqlite::memory:")
+                    .await
+                    .unwrap();
+                database::run_migrations(&db).await.unwrap();
+                let client = start(&db).await;
+
+                let gtc = TimeInForce::GoodTillCancel;
+                client.buy(5, dec!(2), gtc, None, None).await.unwrap();
+                let mut subscription = client.subscribe(Channels::default()).await.unwrap();
+                let snapshot = subscription.snapshot;
+                assert_eq!(snapshot.sequence, 1);
+                let depth = snapshot.depth.unwrap();
+                assert_eq!(depth.buy.len(), 1);
+                assert_eq!(depth.buy[0].quantity, 5);
+                assert_eq!(snapshot.orders.unwrap().buy.len(), 1);
+
+                client.sell(3, dec!(1), gtc, None, None).await.unwrap();
+                client.cancel(Uuid::new_v4(), None).await.unwrap();
+                let events = client.buy(1, dec!(1.5), gtc, None, None).await.unwrap();
+                let Some(Event::Accepted { order, .. }) = events.first().map(|event| &event.event)
+                else {
+                    panic!("Wrong events={:?}", events);
+                };
+                client.update(order.id, None, 1, dec!(1.8)).await.unwrap();
+
+                let update = subscription.updates.recv().await.unwrap();
+                assert_eq!(update.sequence, 2);
+                assert_eq!(update.trades.len(), 1);
+                assert_eq!(update.trades[0].quantity, 3);
+                let level = |side, price, quantity, order_count| DepthUpdate {
+                    side,
+                    level: PriceLevel {
+                        price,
+                        quantity,
+                        order_count,
+                    },
+                };
+                assert_eq!(
+                    update.depth,
+                    vec![
+                        level(OrderType::Sell, dec!(1), 0, 0),
+                        level(OrderType::Buy, dec!(2), 2, 1),
+                    ]
+                );
+                let sequences: Vec<Option<u64>> =
+                    update.orders.iter().map(|event| event.sequence).collect();
+                assert_eq!(sequences, vec![Some(2), Some(3)]);
+
+                // The rejected cancel is not published.
+                let update = subscription.updates.recv().await.unwrap();
+                assert_eq!(update.sequence, 3);
+                assert_eq!(update.depth, vec![level(OrderType::Buy, dec!(1.5), 1, 1)]);
+
+                let update = subscription.updates.recv().await.unwrap();
+                assert_eq!(update.sequence, 4);
+                assert!(update.trades.is_empty());
+                assert_eq!(
+                    update.depth,
+                    vec![
+                        level(OrderType::Buy, dec!(1.8), 1, 1),
+                        level(OrderType::Buy, dec!(1.5), 0, 0),
+                    ]
+                );
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_subscription_never_shows_owners_or_iceberg_reserves() {
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let db = SqlitePoolOptions::new()
+                    .max_connections(1)
+                    .connect("sqlite::memory:")
+                    .await
+                    .unwrap();
+                database::run_migrations(&db).await.unwrap();
+                let client = start(&db).await;
+
+                let gtc = TimeInForce::GoodTillCancel;
+                let owner = Some("alice".to_owned());
+                client.sell(1, dec!(5), gtc, None, owner).await.unwrap();
+                let mut subscription = client.subscribe(Channels::default()).await.unwrap();
+                let snapshot = serde_json::to_string(&subscription.snapshot).unwrap();
+                assert!(snapshot.contains(r#""quantity":1"#), "{}", snapshot);
+                assert!(!snapshot.contains("alice"), "{}", snapshot);
+
+                client.buy_iceberg(7, 2, dec!(5), gtc).await.unwrap();
+                let update = subscription.updates.recv().await.unwrap();
+                assert_eq!(update.trades.len(), 1);
+                let update = serde_json::to_string(&update).unwrap();
+                assert!(!update.contains("alice"), "{}", update);
+                for quantity in [7, 6] {
+                    let hidden = format!(r#""quantity":{}"#, quantity);
+                    assert!(!update.contains(&hidden), "{}", update);
+                }
+                assert!(!update.contains("reserve_quantity"), "{}", update);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_tail_receives_events_as_logged() {
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let db = SqlitePoolOptions::new()
+                    .max_connections(1)
+                    .connect("sqlite::memory:")
+                    .await
+                    .unwrap();
+                database::run_migrations(&db).await.unwrap();
+                let client = start(&db).await;
+
+                let gtc = TimeInForce::GoodTillCancel;
+                client.buy(5, dec!(2), gtc, None, None).await.unwrap();
+                let mut tail = client.tail().await.unwrap();
+                assert_eq!(tail.last_sequence, 1);
+
+                client.sell(3, dec!(1), gtc, None, None).await.unwrap();
+                let sell = tail.events.recv().await.unwrap();
+                let fill = tail.events.recv().await.unwrap();
+                assert_eq!((sell.sequence, sell.event_type.as_str()), (2, "sell"));
+                assert_eq!((fill.sequence, fill.event_type.as_str()), (3, "fill"));
+                assert_eq!(
+                    database::load_logged_events(&db, "test", 1, 10)
+                        .await
+                        .unwrap(),
+                    vec![sell, fill]
+                );
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_saved_events_are_numbered_without_gaps_across_restarts() {
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let db = SqlitePoolOptions::new()
+                    .max_connections(1)
+                    .connect("sqlite::memory:")
+                    .await
+                    .unwrap();
+                database::run_migrations(&db).await.unwrap();
+                let (client, actor) = launch(&db, "test", SnapshotPolicy::default()).await;
+                let sequences = |events: Vec<SequencedEvent>| -> Vec<Option<u64>> {
+                    events.iter().map(|event| event.sequence).collect()
+                };
+
+                let gtc = TimeInForce::GoodTillCancel;
+                let events = client.buy(5, dec!(2), gtc, None, None).await.unwrap();
+                assert_eq!(sequences(events), vec![Some(1)]);
+                let events = client.cancel(Uuid::new_v4(), None).await.unwrap();
+                assert_eq!(sequences(events), vec![None]);
+                let events = client.sell(3, dec!(1), gtc, None, None).await.unwrap();
+                assert_eq!(sequences(events), vec![Some(2), Some(3)]);
+                let subscription = client.subscribe(Channels::default()).await.unwrap();
+                assert_eq!(subscription.snapshot.sequence, 2);
+
+                drop(subscription);
+                drop(client);
+                actor.await.unwrap().unwrap();
+                let client = start(&db).await;
+                let mut subscription = client.subscribe(Channels::default()).await.unwrap();
+                assert_eq!(subscription.snapshot.sequence, 3);
+                let events = client.market_sell(2).await.unwrap();
+                assert_eq!(sequences(events), vec![Some(4), Some(5)]);
+                let logged = database::load_logged_events(&db, "test", 0, 10)
+                    .await
+                    .unwrap();
+                let logged: Vec<u64> = logged.iter().map(|event| event.sequence).collect();
+                assert_eq!(logged, vec![1, 2, 3, 4, 5]);
+                let update = subscription.updates.recv().await.unwrap();
+                assert_eq!(update.sequence, 4);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_snapshots_count_the_events_of_their_own_market() {
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let db = SqlitePoolOptions::new()
+                    .max_connections(1)
+                    .connect("sqlite::memory:")
+                    .await
+                    .unwrap();
+                database::run_migrations(&db).await.unwrap();
+                let snapshot_policy = SnapshotPolicy {
+                    every_events: Some(3),
+                    every: None,
+                };
+                let (quiet, _) = launch(&db, "quiet", snapshot_policy).await;
+                let (busy, actor) = launch(&db, "busy", snapshot_policy).await;
+
+                let gtc = TimeInForce::GoodTillCancel;
+                quiet.buy(1, dec!(1), gtc, None, None).await.unwrap();
+                for _ in 0..3 {
+                    busy.buy(1, dec!(1), gtc, None, None).await.unwrap();
+                }
+                quiet.buy(1, dec!(1), gtc, None, None).await.unwrap();
+                let snapshot = database::load_latest_snapshot(&db, "busy").await.unwrap();
+                assert_eq!(snapshot.map(|snapshot| snapshot.last_event_id), Some(4));
+                let snapshot = database::load_latest_snapshot(&db, "quiet").await.unwrap();
+                assert!(snapshot.is_none());
+
+                quiet.buy(1, dec!(1), gtc, None, None).await.unwrap();
+                let snapshot = database::load_latest_snapshot(&db, "quiet").await.unwrap();
+                assert_eq!(snapshot.map(|snapshot| snapshot.last_event_id), Some(6));
+
+                drop(busy);
+                actor.await.unwrap().unwrap();
+                let (restarted, _) = launch(&db, "busy", snapshot_policy).await;
+                for _ in 0..2 {
+                    restarted.buy(1, dec!(1), gtc, None, None).await.unwrap();
+                }
+                let snapshot = database::load_latest_snapshot(&db, "busy").await.unwrap();
+                assert_eq!(snapshot.map(|snapshot| snapshot.last_event_id), Some(4));
+                restarted.buy(1, dec!(1), gtc, None, None).await.unwrap();
+                let snapshot = database::load_latest_snapshot(&db, "busy").await.unwrap();
+                assert_eq!(snapshot.map(|snapshot| snapshot.last_event_id), Some(9));
+            })
+            .await;
+    }
 }
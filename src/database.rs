@@ -1,8 +1,21 @@
-use crate::{order_book::Event, Config};
-use anyhow::Result;
+use std::collections::HashMap;
+
+use crate::{
+    order_book::{
+        Event, InstrumentRules, Order, OrderBookState, OrderKind, OrderType, PostOnly, TimeInForce,
+        Trade,
+    },
+    registry::Market,
+    Config,
+};
+use anyhow::{anyhow, Result};
 use chrono::{DateTime, Utc};
 use rust_decimal::prelude::*;
-use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use serde::Serialize;
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    QueryBuilder,
+};
 use uuid::Uuid;
 
 type SqlxPool = sqlx::Pool<sqlx::Sqlite>;
@@ -33,112 +46,1677 @@ pub async fn run_health_check(db: &SqlxPool) -> Result<()> {
     Ok(())
 }
 
-#[derive(Debug, Clone, Copy, sqlx::Type)]
-enum EventType {
+/// The types of the events saved in the event log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+pub enum EventType {
     Buy,
     Sell,
     Fill,
     Cancel,
+    Expire,
+    Trigger,
+    Replenish,
+    Exhaust,
+    Amend,
+    SelfTradeCancelNewest,
+    SelfTradeCancelOldest,
+    SelfTradeCancelBoth,
+    SelfTradeDecrement,
 }
 
 impl EventType {
-    fn as_str(&self) -> &'static str {
+    pub fn as_str(&self) -> &'static str {
         match self {
             Self::Buy => "buy",
             Self::Sell => "sell",
             Self::Fill => "fill",
             Self::Cancel => "cancel",
+            Self::Expire => "expire",
+            Self::Trigger => "trigger",
+            Self::Replenish => "replenish",
+            Self::Exhaust => "exhaust",
+            Self::Amend => "amend",
+            Self::SelfTradeCancelNewest => "self_trade_cancel_newest",
+            Self::SelfTradeCancelOldest => "self_trade_cancel_oldest",
+            Self::SelfTradeCancelBoth => "self_trade_cancel_both",
+            Self::SelfTradeDecrement => "self_trade_decrement",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "buy" => Some(Self::Buy),
+            "sell" => Some(Self::Sell),
+            "fill" => Some(Self::Fill),
+            "cancel" => Some(Self::Cancel),
+            "expire" => Some(Self::Expire),
+            "trigger" => Some(Self::Trigger),
+            "replenish" => Some(Self::Replenish),
+            "exhaust" => Some(Self::Exhaust),
+            "amend" => Some(Self::Amend),
+            "self_trade_cancel_newest" => Some(Self::SelfTradeCancelNewest),
+            "self_trade_cancel_oldest" => Some(Self::SelfTradeCancelOldest),
+            "self_trade_cancel_both" => Some(Self::SelfTradeCancelBoth),
+            "self_trade_decrement" => Some(Self::SelfTradeDecrement),
+            _ => None,
         }
     }
 }
 
+fn order_kind_as_str(kind: OrderKind) -> &'static str {
+    match kind {
+        OrderKind::Limit => "limit",
+        OrderKind::Market => "market",
+    }
+}
+
+fn parse_order_kind(value: Option<&str>) -> Result<OrderKind> {
+    match value {
+        None | Some("limit") => Ok(OrderKind::Limit),
+        Some("market") => Ok(OrderKind::Market),
+        Some(value) => Err(anyhow!("Unknown order_kind={}", value)),
+    }
+}
+
+fn time_in_force_as_str(time_in_force: TimeInForce) -> &'static str {
+    match time_in_force {
+        TimeInForce::GoodTillCancel => "gtc",
+        TimeInForce::ImmediateOrCancel => "ioc",
+        TimeInForce::FillOrKill => "fok",
+        TimeInForce::Day => "day",
+    }
+}
+
+fn parse_time_in_force(value: Option<&str>) -> Result<TimeInForce> {
+    match value {
+        None | Some("gtc") => Ok(TimeInForce::GoodTillCancel),
+        Some("ioc") => Ok(TimeInForce::ImmediateOrCancel),
+        Some("fok") => Ok(TimeInForce::FillOrKill),
+        Some("day") => Ok(TimeInForce::Day),
+        Some(value) => Err(anyhow!("Unknown time_in_force={}", value)),
+    }
+}
+
+fn post_only_as_str(post_only: PostOnly) -> &'static str {
+    match post_only {
+        PostOnly::Reject => "reject",
+        PostOnly::Reprice => "reprice",
+    }
+}
+
+fn parse_post_only(value: Option<&str>) -> Result<Option<PostOnly>> {
+    match value {
+        None => Ok(None),
+        Some("reject") => Ok(Some(PostOnly::Reject)),
+        Some("reprice") => Ok(Some(PostOnly::Reprice)),
+        Some(value) => Err(anyhow!("Unknown post_only={}", value)),
+    }
+}
+
 #[derive(Debug)]
-struct EventRow {
+struct EventRow<'a> {
     ts: DateTime<Utc>,
     event_type: &'static str,
     order_id: Uuid,
+    order_ts: Option<DateTime<Utc>>,
     order_quantity: Option<i32>,
-    order_price: Option<f64>,
+    order_price: Option<String>,
     counterpart_id: Option<Uuid>,
     counterpart_quantity: Option<i32>,
-    counterpart_price: Option<f64>,
+    counterpart_price: Option<String>,
+    order_kind: Option<&'static str>,
+    time_in_force: Option<&'static str>,
+    trigger_price: Option<String>,
+    display_quantity: Option<i32>,
+    reserve_quantity: Option<i32>,
+    post_only: Option<&'static str>,
+    owner: Option<&'a str>,
+}
+
+impl EventRow<'_> {
+    /// A row only referencing an order, already known from its acceptance.
+    fn order_event(ts: DateTime<Utc>, event_type: EventType, order_id: Uuid) -> Self {
+        EventRow {
+            ts,
+            event_type: event_type.as_str(),
+            order_id,
+            order_ts: None,
+            order_quantity: None,
+            order_price: None,
+            counterpart_id: None,
+            counterpart_quantity: None,
+            counterpart_price: None,
+            order_kind: None,
+            time_in_force: None,
+            trigger_price: None,
+            display_quantity: None,
+            reserve_quantity: None,
+            post_only: None,
+            owner: None,
+        }
+    }
+
+    /// A row referencing both orders of a prevented self-trade, with the
+    /// quantities they had at that moment.
+    fn self_trade_event(
+        ts: DateTime<Utc>,
+        event_type: EventType,
+        order: &Order,
+        counterpart: &Order,
+    ) -> Self {
+        EventRow {
+            order_quantity: Some(order.quantity as i32),
+            display_quantity: order.display_quantity.map(|quantity| quantity as i32),
+            counterpart_id: Some(counterpart.id),
+            counterpart_quantity: Some(counterpart.quantity as i32),
+            ..EventRow::order_event(ts, event_type, order.id)
+        }
+    }
 }
 
-impl TryFrom<&Event> for EventRow {
+impl<'a> TryFrom<&'a Event> for EventRow<'a> {
     type Error = ();
 
-    fn try_from(value: &Event) -> Result<Self, Self::Error> {
+    fn try_from(value: &'a Event) -> Result<Self, Self::Error> {
         match value {
             Event::Filled {
                 ts,
                 order,
                 counterpart,
+                ..
             } => Ok(EventRow {
-                ts: ts.clone(),
-                event_type: EventType::Fill.as_str(),
-                order_id: order.id.clone(),
                 order_quantity: Some(order.quantity as i32),
-                order_price: Some(order.price.to_f64().unwrap()),
+                order_price: Some(order.price.to_string()),
+                display_quantity: order.display_quantity.map(|quantity| quantity as i32),
                 counterpart_id: Some(counterpart.id),
                 counterpart_quantity: Some(counterpart.quantity as i32),
-                counterpart_price: Some(counterpart.price.to_f64().unwrap()),
+                counterpart_price: Some(counterpart.price.to_string()),
+                ..EventRow::order_event(*ts, EventType::Fill, order.id)
             }),
             Event::Accepted { ts, order } => {
                 let event_type = match order.order_type {
-                    crate::order_book::OrderType::Sell => EventType::Sell,
-                    crate::order_book::OrderType::Buy => EventType::Buy,
+                    OrderType::Sell => EventType::Sell,
+                    OrderType::Buy => EventType::Buy,
                 };
                 Ok(EventRow {
-                    ts: ts.clone(),
-                    event_type: event_type.as_str(),
-                    order_id: order.id.clone(),
                     order_quantity: Some(order.quantity as i32),
-                    order_price: Some(order.price.to_f64().unwrap()),
-                    counterpart_id: None,
-                    counterpart_quantity: None,
-                    counterpart_price: None,
+                    order_price: Some(order.price.to_string()),
+                    order_kind: Some(order_kind_as_str(order.kind)),
+                    time_in_force: Some(time_in_force_as_str(order.time_in_force)),
+                    trigger_price: order.trigger_price.map(|price| price.to_string()),
+                    display_quantity: order.display_quantity.map(|quantity| quantity as i32),
+                    post_only: order.post_only.map(post_only_as_str),
+                    owner: order.owner.as_deref(),
+                    ..EventRow::order_event(*ts, event_type, order.id)
                 })
             }
-            Event::Canceled { ts, order } => Ok(EventRow {
-                ts: ts.clone(),
-                event_type: EventType::Cancel.as_str(),
-                order_id: order.id.clone(),
-                order_quantity: None,
-                order_price: None,
-                counterpart_id: None,
-                counterpart_quantity: None,
-                counterpart_price: None,
+            Event::Canceled { ts, order } => {
+                Ok(EventRow::order_event(*ts, EventType::Cancel, order.id))
+            }
+            Event::Expired { ts, order } => {
+                Ok(EventRow::order_event(*ts, EventType::Expire, order.id))
+            }
+            Event::Triggered { ts, order } => {
+                Ok(EventRow::order_event(*ts, EventType::Trigger, order.id))
+            }
+            Event::Replenished { ts, order } => Ok(EventRow {
+                order_quantity: Some(order.quantity as i32),
+                reserve_quantity: Some(order.reserve_quantity as i32),
+                ..EventRow::order_event(*ts, EventType::Replenish, order.id)
             }),
+            Event::Exhausted { ts, order } => {
+                Ok(EventRow::order_event(*ts, EventType::Exhaust, order.id))
+            }
+            Event::Amended { ts, order } => Ok(EventRow {
+                order_ts: Some(order.ts),
+                order_quantity: Some(order.quantity as i32),
+                order_price: Some(order.price.to_string()),
+                display_quantity: order.display_quantity.map(|quantity| quantity as i32),
+                reserve_quantity: Some(order.reserve_quantity as i32),
+                ..EventRow::order_event(*ts, EventType::Amend, order.id)
+            }),
+            Event::SelfTradeCanceledNewest {
+                ts,
+                order,
+                counterpart,
+            } => Ok(EventRow::self_trade_event(
+                *ts,
+                EventType::SelfTradeCancelNewest,
+                order,
+                counterpart,
+            )),
+            Event::SelfTradeCanceledOldest {
+                ts,
+                order,
+                counterpart,
+            } => Ok(EventRow::self_trade_event(
+                *ts,
+                EventType::SelfTradeCancelOldest,
+                order,
+                counterpart,
+            )),
+            Event::SelfTradeCanceledBoth {
+                ts,
+                order,
+                counterpart,
+            } => Ok(EventRow::self_trade_event(
+                *ts,
+                EventType::SelfTradeCancelBoth,
+                order,
+                counterpart,
+            )),
+            Event::SelfTradeDecremented {
+                ts,
+                order,
+                counterpart,
+            } => Ok(EventRow::self_trade_event(
+                *ts,
+                EventType::SelfTradeDecrement,
+                order,
+                counterpart,
+            )),
             Event::Rejected { .. } => Err(()),
             Event::State { .. } => Err(()),
+            Event::Depth { .. } => Err(()),
         }
     }
 }
 
-pub async fn save_events(db: &SqlxPool, events: &Vec<Event>) -> Result<()> {
+/// Whether the event is saved in the event log, the events not changing the
+/// order book are not.
+pub fn is_logged(event: &Event) -> bool {
+    EventRow::try_from(event).is_ok()
+}
+
+fn order_type_as_str(order_type: OrderType) -> &'static str {
+    match order_type {
+        OrderType::Sell => EventType::Sell.as_str(),
+        OrderType::Buy => EventType::Buy.as_str(),
+    }
+}
+
+fn parse_order_type(value: &str) -> Result<OrderType> {
+    match EventType::parse(value) {
+        Some(EventType::Sell) => Ok(OrderType::Sell),
+        Some(EventType::Buy) => Ok(OrderType::Buy),
+        _ => Err(anyhow!("Unknown order_type={}", value)),
+    }
+}
+
+/// Persists the events in a single transaction, along with the trades of the
+/// fills, returning the saved events as logged, the events not changing the
+/// order book are not saved. The saved events are numbered in order, after
+/// `last_sequence`, the last number given to an event of the market.
+pub async fn save_events(
+    db: &SqlxPool,
+    ticker: &str,
+    last_sequence: u64,
+    events: &[Event],
+) -> Result<Vec<LoggedEvent>> {
+    let trade_sql = r#"INSERT INTO orderbook_trade
+    (id, event_id, ts, price, quantity, maker_order_id, taker_order_id, aggressor_side, maker_remaining_quantity, taker_remaining_quantity, ticker)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)"#;
     let sql = r#"INSERT INTO orderbook_event
-    (ts, event_type, order_id, order_quantity, order_price, counterpart_id, counterpart_quantity, counterpart_price)
-    VALUES ($1, $2, $3, $4, $5, $6, $7, $8)"#;
+    (ts, event_type, order_id, order_quantity, order_price, counterpart_id, counterpart_quantity, counterpart_price, order_kind, time_in_force, trigger_price, display_quantity, reserve_quantity, post_only, owner, order_ts, ticker, sequence)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18)"#;
 
-    let rows: Vec<EventRow> = events.iter().filter_map(|e| e.try_into().ok()).collect();
+    let rows: Vec<(&Event, EventRow)> = events
+        .iter()
+        .filter_map(|e| Some((e, e.try_into().ok()?)))
+        .collect();
     if rows.is_empty() {
-        return Ok(());
+        return Ok(vec![]);
     }
 
+    let mut logged = Vec::with_capacity(rows.len());
     let mut tx = db.begin().await?;
-    for row in rows {
-        let _ = sqlx::query(sql)
+    for (event, row) in rows {
+        let sequence = last_sequence + logged.len() as u64 + 1;
+        let result = sqlx::query(sql)
             .bind(row.ts)
             .bind(row.event_type)
             .bind(row.order_id)
             .bind(row.order_quantity)
-            .bind(row.order_price)
+            .bind(row.order_price.clone())
             .bind(row.counterpart_id)
             .bind(row.counterpart_quantity)
-            .bind(row.counterpart_price)
+            .bind(row.counterpart_price.clone())
+            .bind(row.order_kind)
+            .bind(row.time_in_force)
+            .bind(row.trigger_price.clone())
+            .bind(row.display_quantity)
+            .bind(row.reserve_quantity)
+            .bind(row.post_only)
+            .bind(row.owner)
+            .bind(row.order_ts)
+            .bind(ticker)
+            .bind(sequence as i64)
+            .execute(&mut tx)
+            .await?;
+        let event_id = result.last_insert_rowid();
+        if let Event::Filled { trade, .. } = event {
+            sqlx::query(trade_sql)
+                .bind(trade.id)
+                .bind(event_id)
+                .bind(trade.ts)
+                .bind(trade.price.to_string())
+                .bind(trade.quantity as i32)
+                .bind(trade.maker_order_id)
+                .bind(trade.taker_order_id)
+                .bind(order_type_as_str(trade.aggressor_side))
+                .bind(trade.maker_remaining_quantity as i32)
+                .bind(trade.taker_remaining_quantity as i32)
+                .bind(ticker)
+                .execute(&mut tx)
+                .await?;
+        }
+        logged.push(LoggedEvent::try_from(StoredEventRow::new(
+            event_id, ticker, sequence, row,
+        ))?);
+    }
+    tx.commit().await?;
+    Ok(logged)
+}
+
+pub async fn last_event_id(db: &SqlxPool, ticker: &str) -> Result<i64> {
+    let id: i64 =
+        sqlx::query_scalar("SELECT COALESCE(MAX(id), 0) FROM orderbook_event WHERE ticker = $1")
+            .bind(ticker)
+            .fetch_one(db)
+            .await?;
+    Ok(id)
+}
+
+/// The number of the last event saved of a market, 0 before the first one.
+pub async fn last_sequence(db: &SqlxPool, ticker: &str) -> Result<u64> {
+    let sequence: i64 = sqlx::query_scalar(
+        "SELECT COALESCE(MAX(sequence), 0) FROM orderbook_event WHERE ticker = $1",
+    )
+    .bind(ticker)
+    .fetch_one(db)
+    .await?;
+    Ok(u64::try_from(sequence)?)
+}
+
+/// The number of the last event of a market saved up to the event `id` of
+/// the event log, 0 before the first one.
+pub async fn sequence_at(db: &SqlxPool, ticker: &str, id: i64) -> Result<u64> {
+    let sequence: i64 = sqlx::query_scalar(
+        "SELECT COALESCE(MAX(sequence), 0) FROM orderbook_event WHERE ticker = $1 AND id <= $2",
+    )
+    .bind(ticker)
+    .bind(id)
+    .fetch_one(db)
+    .await?;
+    Ok(u64::try_from(sequence)?)
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct StoredEventRow {
+    id: i64,
+    ticker: String,
+    sequence: i64,
+    ts: DateTime<Utc>,
+    event_type: String,
+    order_id: Uuid,
+    order_ts: Option<DateTime<Utc>>,
+    order_quantity: Option<i32>,
+    order_price: Option<String>,
+    counterpart_id: Option<Uuid>,
+    counterpart_quantity: Option<i32>,
+    counterpart_price: Option<String>,
+    order_kind: Option<String>,
+    time_in_force: Option<String>,
+    trigger_price: Option<String>,
+    display_quantity: Option<i32>,
+    reserve_quantity: Option<i32>,
+    post_only: Option<String>,
+    owner: Option<String>,
+}
+
+impl StoredEventRow {
+    fn new(id: i64, ticker: &str, sequence: u64, row: EventRow) -> Self {
+        StoredEventRow {
+            id,
+            ticker: ticker.to_owned(),
+            sequence: sequence as i64,
+            ts: row.ts,
+            event_type: row.event_type.to_owned(),
+            order_id: row.order_id,
+            order_ts: row.order_ts,
+            order_quantity: row.order_quantity,
+            order_price: row.order_price,
+            counterpart_id: row.counterpart_id,
+            counterpart_quantity: row.counterpart_quantity,
+            counterpart_price: row.counterpart_price,
+            order_kind: row.order_kind.map(str::to_owned),
+            time_in_force: row.time_in_force.map(str::to_owned),
+            trigger_price: row.trigger_price,
+            display_quantity: row.display_quantity,
+            reserve_quantity: row.reserve_quantity,
+            post_only: row.post_only.map(str::to_owned),
+            owner: row.owner.map(str::to_owned),
+        }
+    }
+}
+
+const EVENT_COLUMNS: &str = r#"id, ticker, sequence, ts, event_type, order_id, order_ts, order_quantity, order_price,
+    counterpart_id, counterpart_quantity, counterpart_price, order_kind, time_in_force,
+    trigger_price, display_quantity, reserve_quantity,
+    post_only, owner"#;
+
+/// An event as saved in the event log, identified by its position in it
+/// and numbered by its position among the events of its market.
+/// Besides accepted orders, events only reference the orders by id with the
+/// quantities and prices they changed. Like the book, it only shows the
+/// displayed slice of iceberg orders and never their reserve.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LoggedEvent {
+    pub id: i64,
+    pub ticker: String,
+    pub sequence: u64,
+    pub ts: DateTime<Utc>,
+    pub event_type: String,
+    pub order_id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub order_ts: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub order_quantity: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub order_price: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub counterpart_id: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub counterpart_quantity: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub counterpart_price: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub order_kind: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_in_force: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trigger_price: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_quantity: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_only: Option<String>,
+    /// Kept for the server, never published.
+    #[serde(skip)]
+    pub owner: Option<String>,
+}
+
+impl TryFrom<StoredEventRow> for LoggedEvent {
+    type Error = anyhow::Error;
+
+    fn try_from(row: StoredEventRow) -> Result<Self> {
+        let display_quantity = optional_quantity(row.display_quantity)?;
+        let order_quantity = match (optional_quantity(row.order_quantity)?, display_quantity) {
+            (Some(quantity), Some(display_quantity)) => Some(quantity.min(display_quantity)),
+            (quantity, _) => quantity,
+        };
+        Ok(LoggedEvent {
+            id: row.id,
+            ticker: row.ticker,
+            sequence: u64::try_from(row.sequence)?,
+            ts: row.ts,
+            event_type: row.event_type,
+            order_id: row.order_id,
+            order_ts: row.order_ts,
+            order_quantity,
+            order_price: optional_price(row.order_price)?,
+            counterpart_id: row.counterpart_id,
+            counterpart_quantity: optional_quantity(row.counterpart_quantity)?,
+            counterpart_price: optional_price(row.counterpart_price)?,
+            order_kind: row.order_kind,
+            time_in_force: row.time_in_force,
+            trigger_price: optional_price(row.trigger_price)?,
+            display_quantity,
+            post_only: row.post_only,
+            owner: row.owner,
+        })
+    }
+}
+
+/// Loads up to `limit` events of the event log of a market numbered after
+/// `after_sequence`, in the order they were saved.
+pub async fn load_logged_events(
+    db: &SqlxPool,
+    ticker: &str,
+    after_sequence: u64,
+    limit: i64,
+) -> Result<Vec<LoggedEvent>> {
+    let sql = format!(
+        "SELECT {EVENT_COLUMNS} FROM orderbook_event WHERE ticker = $1 AND sequence > $2 ORDER BY sequence LIMIT $3"
+    );
+    let rows: Vec<StoredEventRow> = sqlx::query_as(&sql)
+        .bind(ticker)
+        .bind(after_sequence as i64)
+        .bind(limit)
+        .fetch_all(db)
+        .await?;
+    rows.into_iter().map(LoggedEvent::try_from).collect()
+}
+
+/// Which events of the event log of a market to query, without filters all
+/// of them. Times go from `from` to right before `to`, sequences from
+/// `from_sequence` to `to_sequence`, and an order matches its own events as
+/// well as the fills it is the counterpart of.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EventQuery {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub event_types: Vec<EventType>,
+    pub order_id: Option<Uuid>,
+    pub from_sequence: Option<u64>,
+    pub to_sequence: Option<u64>,
+}
+
+/// A page of queried events, the next page starts after `next_cursor`, only
+/// there when there are more events.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EventPage {
+    pub events: Vec<LoggedEvent>,
+    pub next_cursor: Option<String>,
+}
+
+/// Cursors are opaque to clients, they are the sequence of the last event of
+/// a page.
+pub fn parse_cursor(cursor: &str) -> Option<u64> {
+    cursor.parse().ok()
+}
+
+/// Loads up to `limit` events of the event log of a market matching the
+/// query, numbered after `after_sequence`, in the order they were saved.
+pub async fn query_events(
+    db: &SqlxPool,
+    ticker: &str,
+    query: &EventQuery,
+    after_sequence: u64,
+    limit: i64,
+) -> Result<EventPage> {
+    let mut sql = QueryBuilder::new(format!(
+        "SELECT {EVENT_COLUMNS} FROM orderbook_event WHERE ticker = "
+    ));
+    sql.push_bind(ticker)
+        .push(" AND sequence > ")
+        .push_bind(after_sequence as i64);
+    if let Some(from) = query.from {
+        sql.push(" AND ts >= ").push_bind(from);
+    }
+    if let Some(to) = query.to {
+        sql.push(" AND ts < ").push_bind(to);
+    }
+    if !query.event_types.is_empty() {
+        sql.push(" AND event_type IN (");
+        let mut event_types = sql.separated(", ");
+        for event_type in &query.event_types {
+            event_types.push_bind(event_type.as_str());
+        }
+        sql.push(")");
+    }
+    if let Some(order_id) = query.order_id {
+        sql.push(" AND (order_id = ")
+            .push_bind(order_id)
+            .push(" OR counterpart_id = ")
+            .push_bind(order_id)
+            .push(")");
+    }
+    if let Some(from_sequence) = query.from_sequence {
+        sql.push(" AND sequence >= ")
+            .push_bind(from_sequence as i64);
+    }
+    if let Some(to_sequence) = query.to_sequence {
+        sql.push(" AND sequence <= ").push_bind(to_sequence as i64);
+    }
+    // One more event tells whether there is a next page.
+    sql.push(" ORDER BY sequence LIMIT ").push_bind(limit + 1);
+    let rows: Vec<StoredEventRow> = sql.build_query_as().fetch_all(db).await?;
+    let mut events = rows
+        .into_iter()
+        .map(LoggedEvent::try_from)
+        .collect::<Result<Vec<_>>>()?;
+    let next_cursor = if events.len() as i64 > limit {
+        events.truncate(limit as usize);
+        events.last().map(|event| event.sequence.to_string())
+    } else {
+        None
+    };
+    Ok(EventPage {
+        events,
+        next_cursor,
+    })
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct TradeRow {
+    id: Uuid,
+    event_id: i64,
+    ts: DateTime<Utc>,
+    price: String,
+    quantity: i32,
+    maker_order_id: Uuid,
+    taker_order_id: Uuid,
+    aggressor_side: String,
+    maker_remaining_quantity: i32,
+    taker_remaining_quantity: i32,
+}
+
+impl TryFrom<TradeRow> for Trade {
+    type Error = anyhow::Error;
+
+    fn try_from(row: TradeRow) -> Result<Self> {
+        Ok(Trade {
+            id: row.id,
+            ts: row.ts,
+            price: price(Some(row.price))?,
+            quantity: quantity(Some(row.quantity))?,
+            maker_order_id: row.maker_order_id,
+            taker_order_id: row.taker_order_id,
+            aggressor_side: parse_order_type(&row.aggressor_side)?,
+            maker_remaining_quantity: quantity(Some(row.maker_remaining_quantity))?,
+            taker_remaining_quantity: quantity(Some(row.taker_remaining_quantity))?,
+        })
+    }
+}
+
+const TRADE_COLUMNS: &str = r#"id, event_id, ts, price, quantity, maker_order_id, taker_order_id,
+    aggressor_side, maker_remaining_quantity, taker_remaining_quantity"#;
+
+/// Loads the trades of a market made since `since`, in the order they were
+/// made.
+pub async fn load_trades(db: &SqlxPool, ticker: &str, since: DateTime<Utc>) -> Result<Vec<Trade>> {
+    let sql = format!(
+        "SELECT {TRADE_COLUMNS} FROM orderbook_trade WHERE ticker = $1 AND ts >= $2 ORDER BY event_id"
+    );
+    let rows: Vec<TradeRow> = sqlx::query_as(&sql)
+        .bind(ticker)
+        .bind(since)
+        .fetch_all(db)
+        .await?;
+    rows.into_iter().map(Trade::try_from).collect()
+}
+
+pub async fn load_last_trade(db: &SqlxPool, ticker: &str) -> Result<Option<Trade>> {
+    let sql = format!(
+        "SELECT {TRADE_COLUMNS} FROM orderbook_trade WHERE ticker = $1 ORDER BY event_id DESC LIMIT 1"
+    );
+    let row: Option<TradeRow> = sqlx::query_as(&sql).bind(ticker).fetch_optional(db).await?;
+    row.map(Trade::try_from).transpose()
+}
+
+/// Prices are stored as decimal text, read back exactly as they were saved.
+fn price(value: Option<String>) -> Result<Decimal> {
+    value
+        .as_deref()
+        .and_then(|value| Decimal::from_str(value).ok())
+        .ok_or_else(|| anyhow!("Invalid price={:?}", value))
+}
+
+fn optional_price(value: Option<String>) -> Result<Option<Decimal>> {
+    value.map(|value| price(Some(value))).transpose()
+}
+
+fn quantity(value: Option<i32>) -> Result<u32> {
+    value
+        .and_then(|quantity| u32::try_from(quantity).ok())
+        .ok_or_else(|| anyhow!("Invalid quantity={:?}", value))
+}
+
+fn optional_quantity(value: Option<i32>) -> Result<Option<u32>> {
+    value.map(|value| quantity(Some(value))).transpose()
+}
+
+/// Loads the persisted events of a market saved after `after_event_id`, in
+/// the order they were saved.
+///
+/// Only accepted orders are stored with their full details, placed at the
+/// time they are accepted, the other events are resolved against the orders
+/// seen before them, starting from the given `orders` which are already on
+/// the book.
+pub async fn load_events(
+    db: &SqlxPool,
+    ticker: &str,
+    after_event_id: i64,
+    orders: impl IntoIterator<Item = Order>,
+) -> Result<Vec<Event>> {
+    let trade_sql =
+        format!("SELECT {TRADE_COLUMNS} FROM orderbook_trade WHERE ticker = $1 AND event_id > $2");
+    let sql = format!(
+        "SELECT {EVENT_COLUMNS} FROM orderbook_event WHERE ticker = $1 AND id > $2 ORDER BY id"
+    );
+
+    let rows: Vec<StoredEventRow> = sqlx::query_as(&sql)
+        .bind(ticker)
+        .bind(after_event_id)
+        .fetch_all(db)
+        .await?;
+    let trade_rows: Vec<TradeRow> = sqlx::query_as(&trade_sql)
+        .bind(ticker)
+        .bind(after_event_id)
+        .fetch_all(db)
+        .await?;
+    let mut trades = HashMap::with_capacity(trade_rows.len());
+    for row in trade_rows {
+        trades.insert(row.event_id, Trade::try_from(row)?);
+    }
+
+    let mut orders: HashMap<Uuid, Order> =
+        orders.into_iter().map(|order| (order.id, order)).collect();
+    let mut events = Vec::with_capacity(rows.len());
+    for row in rows {
+        let event_type = EventType::parse(&row.event_type)
+            .ok_or_else(|| anyhow!("Unknown event_type={}", row.event_type))?;
+        let known = |id: &Uuid| {
+            orders
+                .get(id)
+                .cloned()
+                .ok_or_else(|| anyhow!("Event references unknown order={}", id))
+        };
+        let event = match event_type {
+            EventType::Buy | EventType::Sell => {
+                let order_type = match event_type {
+                    EventType::Sell => OrderType::Sell,
+                    _ => OrderType::Buy,
+                };
+                let order = Order {
+                    order_type,
+                    kind: parse_order_kind(row.order_kind.as_deref())?,
+                    time_in_force: parse_time_in_force(row.time_in_force.as_deref())?,
+                    id: row.order_id,
+                    ts: row.ts,
+                    quantity: quantity(row.order_quantity)?,
+                    price: price(row.order_price)?,
+                    trigger_price: optional_price(row.trigger_price)?,
+                    display_quantity: optional_quantity(row.display_quantity)?,
+                    reserve_quantity: 0,
+                    post_only: parse_post_only(row.post_only.as_deref())?,
+                    owner: row.owner,
+                };
+                orders.insert(order.id, order.clone());
+                Event::Accepted { ts: row.ts, order }
+            }
+            EventType::Fill => {
+                let counterpart_id = row
+                    .counterpart_id
+                    .ok_or_else(|| anyhow!("Fill without counterpart, order={}", row.order_id))?;
+                Event::Filled {
+                    ts: row.ts,
+                    order: Order {
+                        quantity: quantity(row.order_quantity)?,
+                        price: price(row.order_price)?,
+                        ..known(&row.order_id)?
+                    },
+                    counterpart: Order {
+                        quantity: quantity(row.counterpart_quantity)?,
+                        price: price(row.counterpart_price)?,
+                        ..known(&counterpart_id)?
+                    },
+                    trade: trades
+                        .remove(&row.id)
+                        .ok_or_else(|| anyhow!("Fill without trade, event={}", row.id))?,
+                }
+            }
+            EventType::Cancel => Event::Canceled {
+                ts: row.ts,
+                order: known(&row.order_id)?,
+            },
+            EventType::Expire => Event::Expired {
+                ts: row.ts,
+                order: known(&row.order_id)?,
+            },
+            EventType::Trigger => Event::Triggered {
+                ts: row.ts,
+                order: known(&row.order_id)?,
+            },
+            EventType::Replenish => {
+                let order = Order {
+                    ts: row.ts,
+                    quantity: quantity(row.order_quantity)?,
+                    reserve_quantity: quantity(row.reserve_quantity)?,
+                    ..known(&row.order_id)?
+                };
+                orders.insert(order.id, order.clone());
+                Event::Replenished { ts: row.ts, order }
+            }
+            EventType::Exhaust => Event::Exhausted {
+                ts: row.ts,
+                order: Order {
+                    quantity: 0,
+                    reserve_quantity: 0,
+                    ..known(&row.order_id)?
+                },
+            },
+            EventType::Amend => {
+                let order = Order {
+                    ts: row.order_ts.unwrap_or(row.ts),
+                    quantity: quantity(row.order_quantity)?,
+                    price: price(row.order_price)?,
+                    reserve_quantity: quantity(row.reserve_quantity)?,
+                    ..known(&row.order_id)?
+                };
+                orders.insert(order.id, order.clone());
+                Event::Amended { ts: row.ts, order }
+            }
+            EventType::SelfTradeCancelNewest
+            | EventType::SelfTradeCancelOldest
+            | EventType::SelfTradeCancelBoth
+            | EventType::SelfTradeDecrement => {
+                let counterpart_id = row.counterpart_id.ok_or_else(|| {
+                    anyhow!("Self-trade without counterpart, order={}", row.order_id)
+                })?;
+                let order = Order {
+                    quantity: quantity(row.order_quantity)?,
+                    ..known(&row.order_id)?
+                };
+                let counterpart = Order {
+                    quantity: quantity(row.counterpart_quantity)?,
+                    ..known(&counterpart_id)?
+                };
+                match event_type {
+                    EventType::SelfTradeCancelNewest => Event::SelfTradeCanceledNewest {
+                        ts: row.ts,
+                        order,
+                        counterpart,
+                    },
+                    EventType::SelfTradeCancelOldest => Event::SelfTradeCanceledOldest {
+                        ts: row.ts,
+                        order,
+                        counterpart,
+                    },
+                    EventType::SelfTradeCancelBoth => Event::SelfTradeCanceledBoth {
+                        ts: row.ts,
+                        order,
+                        counterpart,
+                    },
+                    _ => Event::SelfTradeDecremented {
+                        ts: row.ts,
+                        order,
+                        counterpart,
+                    },
+                }
+            }
+        };
+        events.push(event);
+    }
+    Ok(events)
+}
+
+#[derive(Debug)]
+pub struct Snapshot {
+    pub ts: DateTime<Utc>,
+    pub last_event_id: i64,
+    pub state: OrderBookState,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct SnapshotRow {
+    id: i64,
+    ts: DateTime<Utc>,
+    last_event_id: i64,
+    last_price: Option<String>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct SnapshotOrderRow {
+    order_type: String,
+    order_id: Uuid,
+    order_ts: DateTime<Utc>,
+    order_quantity: i32,
+    order_price: String,
+    time_in_force: String,
+    order_kind: String,
+    trigger_price: Option<String>,
+    display_quantity: Option<i32>,
+    reserve_quantity: i32,
+    post_only: Option<String>,
+    owner: Option<String>,
+}
+
+/// Saves the snapshot of a market, replacing any previously saved one.
+pub async fn save_snapshot(db: &SqlxPool, ticker: &str, snapshot: &Snapshot) -> Result<()> {
+    let sql = r#"INSERT INTO orderbook_snapshot_order
+    (snapshot_id, order_type, order_id, order_ts, order_quantity, order_price, time_in_force, order_kind, trigger_price, display_quantity, reserve_quantity, post_only, owner)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)"#;
+
+    let mut tx = db.begin().await?;
+    sqlx::query(
+        r#"DELETE FROM orderbook_snapshot_order
+        WHERE snapshot_id IN (SELECT id FROM orderbook_snapshot WHERE ticker = $1)"#,
+    )
+    .bind(ticker)
+    .execute(&mut tx)
+    .await?;
+    sqlx::query("DELETE FROM orderbook_snapshot WHERE ticker = $1")
+        .bind(ticker)
+        .execute(&mut tx)
+        .await?;
+    let snapshot_id = sqlx::query(
+        "INSERT INTO orderbook_snapshot (ts, last_event_id, last_price, ticker) VALUES ($1, $2, $3, $4)",
+    )
+    .bind(snapshot.ts)
+    .bind(snapshot.last_event_id)
+    .bind(
+        snapshot
+            .state
+            .last_price
+            .map(|price| price.to_string()),
+    )
+    .bind(ticker)
+    .execute(&mut tx)
+    .await?
+    .last_insert_rowid();
+    let orders = snapshot.state.buy.iter().chain(snapshot.state.sell.iter());
+    for order in orders.chain(snapshot.state.stop.iter()) {
+        sqlx::query(sql)
+            .bind(snapshot_id)
+            .bind(order_type_as_str(order.order_type))
+            .bind(order.id)
+            .bind(order.ts)
+            .bind(order.quantity as i32)
+            .bind(order.price.to_string())
+            .bind(time_in_force_as_str(order.time_in_force))
+            .bind(order_kind_as_str(order.kind))
+            .bind(order.trigger_price.map(|price| price.to_string()))
+            .bind(order.display_quantity.map(|quantity| quantity as i32))
+            .bind(order.reserve_quantity as i32)
+            .bind(order.post_only.map(post_only_as_str))
+            .bind(order.owner.as_deref())
             .execute(&mut tx)
             .await?;
     }
     tx.commit().await?;
     Ok(())
 }
+
+pub async fn load_latest_snapshot(db: &SqlxPool, ticker: &str) -> Result<Option<Snapshot>> {
+    let snapshot: Option<SnapshotRow> = sqlx::query_as(
+        r#"SELECT id, ts, last_event_id, last_price
+        FROM orderbook_snapshot WHERE ticker = $1 ORDER BY id DESC LIMIT 1"#,
+    )
+    .bind(ticker)
+    .fetch_optional(db)
+    .await?;
+    let Some(snapshot) = snapshot else {
+        return Ok(None);
+    };
+
+    let sql = r#"SELECT order_type, order_id, order_ts, order_quantity, order_price, time_in_force,
+    order_kind, trigger_price, display_quantity, reserve_quantity,
+    post_only, owner
+    FROM orderbook_snapshot_order
+    WHERE snapshot_id = $1"#;
+    let rows: Vec<SnapshotOrderRow> = sqlx::query_as(sql).bind(snapshot.id).fetch_all(db).await?;
+
+    let mut state = OrderBookState {
+        last_price: optional_price(snapshot.last_price)?,
+        ..OrderBookState::default()
+    };
+    for row in rows {
+        let order_type = parse_order_type(&row.order_type)?;
+        let order = Order {
+            order_type,
+            kind: parse_order_kind(Some(&row.order_kind))?,
+            time_in_force: parse_time_in_force(Some(&row.time_in_force))?,
+            id: row.order_id,
+            ts: row.order_ts,
+            quantity: quantity(Some(row.order_quantity))?,
+            price: price(Some(row.order_price))?,
+            trigger_price: optional_price(row.trigger_price)?,
+            display_quantity: optional_quantity(row.display_quantity)?,
+            reserve_quantity: quantity(Some(row.reserve_quantity))?,
+            post_only: parse_post_only(row.post_only.as_deref())?,
+            owner: row.owner,
+        };
+        match (order.trigger_price, order.order_type) {
+            (Some(_), _) => state.stop.push(order),
+            (None, OrderType::Sell) => state.sell.push(order),
+            (None, OrderType::Buy) => state.buy.push(order),
+        }
+    }
+
+    Ok(Some(Snapshot {
+        ts: snapshot.ts,
+        last_event_id: snapshot.last_event_id,
+        state,
+    }))
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct MarketRow {
+    ticker: String,
+    tick_size: String,
+    lot_size: i32,
+    min_quantity: Option<i32>,
+    max_quantity: Option<i32>,
+    min_notional: Option<String>,
+    min_price: Option<String>,
+    max_price: Option<String>,
+    price_band: Option<String>,
+}
+
+impl TryFrom<MarketRow> for Market {
+    type Error = anyhow::Error;
+
+    fn try_from(row: MarketRow) -> Result<Self> {
+        Ok(Market {
+            ticker: row.ticker,
+            tick_size: price(Some(row.tick_size))?,
+            rules: InstrumentRules {
+                lot_size: quantity(Some(row.lot_size))?,
+                min_quantity: optional_quantity(row.min_quantity)?,
+                max_quantity: optional_quantity(row.max_quantity)?,
+                min_notional: optional_price(row.min_notional)?,
+                min_price: optional_price(row.min_price)?,
+                max_price: optional_price(row.max_price)?,
+                price_band: optional_price(row.price_band)?,
+            },
+        })
+    }
+}
+
+/// Saves a listed market, listing again a delisted one with the new
+/// definition. Unless `replace`, an already defined market, listed or not, is
+/// kept as it is, returning whether it was saved.
+pub async fn save_market(db: &SqlxPool, market: &Market, replace: bool) -> Result<bool> {
+    let sql = if replace {
+        r#"INSERT INTO orderbook_market (ticker, tick_size, lot_size, min_quantity, max_quantity,
+        min_notional, min_price, max_price, price_band, listed_ts)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+        ON CONFLICT (ticker) DO UPDATE SET tick_size = excluded.tick_size, lot_size = excluded.lot_size,
+        min_quantity = excluded.min_quantity, max_quantity = excluded.max_quantity,
+        min_notional = excluded.min_notional, min_price = excluded.min_price,
+        max_price = excluded.max_price, price_band = excluded.price_band,
+        listed_ts = excluded.listed_ts, delisted_ts = NULL"#
+    } else {
+        r#"INSERT OR IGNORE INTO orderbook_market (ticker, tick_size, lot_size, min_quantity, max_quantity,
+        min_notional, min_price, max_price, price_band, listed_ts)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)"#
+    };
+    let rules = &market.rules;
+    let result = sqlx::query(sql)
+        .bind(&market.ticker)
+        .bind(market.tick_size.to_string())
+        .bind(rules.lot_size as i32)
+        .bind(rules.min_quantity.map(|quantity| quantity as i32))
+        .bind(rules.max_quantity.map(|quantity| quantity as i32))
+        .bind(rules.min_notional.map(|notional| notional.to_string()))
+        .bind(rules.min_price.map(|price| price.to_string()))
+        .bind(rules.max_price.map(|price| price.to_string()))
+        .bind(rules.price_band.map(|band| band.to_string()))
+        .bind(Utc::now())
+        .execute(db)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn delist_market(db: &SqlxPool, ticker: &str) -> Result<()> {
+    sqlx::query("UPDATE orderbook_market SET delisted_ts = $1 WHERE ticker = $2")
+        .bind(Utc::now())
+        .bind(ticker)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Whether a market was ever listed, delisted since or not.
+pub async fn market_exists(db: &SqlxPool, ticker: &str) -> Result<bool> {
+    let (exists,): (bool,) =
+        sqlx::query_as("SELECT EXISTS (SELECT 1 FROM orderbook_market WHERE ticker = $1)")
+            .bind(ticker)
+            .fetch_one(db)
+            .await?;
+    Ok(exists)
+}
+
+/// Loads the markets listed, in the order they were listed.
+pub async fn load_markets(db: &SqlxPool) -> Result<Vec<Market>> {
+    let rows: Vec<MarketRow> = sqlx::query_as(
+        r#"SELECT ticker, tick_size, lot_size, min_quantity, max_quantity,
+        min_notional, min_price,
+        max_price, price_band
+        FROM orderbook_market WHERE delisted_ts IS NULL ORDER BY listed_ts, ticker"#,
+    )
+    .fetch_all(db)
+    .await?;
+    rows.into_iter().map(Market::try_from).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::order_book::{clock::ManualClock, Command, OrderBook};
+
+    async fn in_memory_db() -> SqlxPool {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        run_migrations(&db).await.unwrap();
+        db
+    }
+
+    /// Saves the events numbered after the last ones of the market.
+    async fn save(db: &SqlxPool, ticker: &str, events: &[Event]) -> Vec<LoggedEvent> {
+        let last_sequence = last_sequence(db, ticker).await.unwrap();
+        save_events(db, ticker, last_sequence, events)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_restore_order_book_from_saved_events() {
+        let db = in_memory_db().await;
+        // Prices beyond the precision of a floating point number.
+        let mut order_book = OrderBook::new("test").with_tick_size(dec!(0.000000000000000001));
+        let commands = vec![
+            Command::Buy {
+                quantity: 1,
+                price: dec!(0.100000000000000001),
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                owner: None,
+            },
+            Command::Sell {
+                quantity: 1,
+                price: dec!(0.1),
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                owner: None,
+            },
+            Command::Buy {
+                quantity: 5,
+                price: dec!(2),
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                owner: None,
+            },
+            Command::Buy {
+                quantity: 3,
+                price: dec!(1.25),
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                owner: None,
+            },
+            Command::Sell {
+                quantity: 10,
+                price: dec!(4.5),
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                owner: None,
+            },
+            Command::Sell {
+                quantity: 2,
+                price: dec!(2),
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                owner: None,
+            },
+            Command::Sell {
+                quantity: 2,
+                price: dec!(1.25),
+                time_in_force: TimeInForce::ImmediateOrCancel,
+                post_only: None,
+                owner: None,
+            },
+            Command::MarketBuy { quantity: 12 },
+            Command::Sell {
+                quantity: 1,
+                price: dec!(4.5),
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                owner: None,
+            },
+            Command::SellIceberg {
+                quantity: 7,
+                display_quantity: 2,
+                price: dec!(5),
+                time_in_force: TimeInForce::GoodTillCancel,
+            },
+            Command::MarketBuy { quantity: 3 },
+            Command::Sell {
+                quantity: 1,
+                price: dec!(1),
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: Some(PostOnly::Reprice),
+                owner: None,
+            },
+            Command::Buy {
+                quantity: 1,
+                price: dec!(1.5),
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                owner: Some("desk".to_owned()),
+            },
+            Command::Sell {
+                quantity: 2,
+                price: dec!(0.5),
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                owner: Some("desk".to_owned()),
+            },
+            Command::Sell {
+                quantity: 1,
+                price: dec!(9007199254740993.10),
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                owner: None,
+            },
+            Command::BuyStop {
+                quantity: 1,
+                trigger_price: dec!(9007199254740993.000000000001),
+                limit_price: None,
+                time_in_force: TimeInForce::GoodTillCancel,
+            },
+        ];
+        let mut trades = vec![];
+        for command in commands {
+            let events = order_book.process(command);
+            save(&db, "test", &events).await;
+            trades.extend(events.into_iter().filter_map(|event| match event {
+                Event::Filled { trade, .. } => Some(trade),
+                _ => None,
+            }));
+        }
+        let buy = order_book.state().buy;
+        let amendments = [(buy[0].id, 2, dec!(8)), (buy[1].id, 1, buy[1].price)];
+        for (id, new_quantity, new_price) in amendments {
+            let events = order_book.process(Command::Update {
+                id,
+                side: None,
+                new_quantity,
+                new_price,
+            });
+            save(&db, "test", &events).await;
+            trades.extend(events.into_iter().filter_map(|event| match event {
+                Event::Filled { trade, .. } => Some(trade),
+                _ => None,
+            }));
+        }
+
+        let events = load_events(&db, "test", 0, vec![]).await.unwrap();
+        let loaded_trades: Vec<Trade> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Filled { trade, .. } => Some(trade.clone()),
+                _ => None,
+            })
+            .collect();
+        assert!(!trades.is_empty());
+        assert_eq!(loaded_trades, trades);
+        let restored = OrderBook::restore("test", OrderBookState::default(), events);
+        assert!(!restored.state().buy.is_empty() && !restored.state().sell.is_empty());
+        assert_eq!(restored.state(), order_book.state());
+        // Exactly the same digits, equal decimals may differ in scale.
+        let digits = |state: OrderBookState| -> Vec<String> {
+            let orders = state.buy.iter().chain(&state.sell).chain(&state.stop);
+            orders
+                .flat_map(|order| [Some(order.price), order.trigger_price])
+                .flatten()
+                .chain(state.last_price)
+                .map(|price| price.to_string())
+                .collect()
+        };
+        assert_eq!(digits(restored.state()), digits(order_book.state()));
+        assert!(digits(restored.state()).contains(&"9007199254740993.10".to_owned()));
+        let trade_digits = |trades: &[Trade]| -> Vec<String> {
+            trades.iter().map(|trade| trade.price.to_string()).collect()
+        };
+        assert_eq!(trade_digits(&loaded_trades), trade_digits(&trades));
+        assert!(trade_digits(&trades).contains(&"0.100000000000000001".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn test_restore_order_book_from_snapshot_and_tail_events() {
+        let db = in_memory_db().await;
+        assert!(load_latest_snapshot(&db, "test").await.unwrap().is_none());
+
+        let mut order_book = OrderBook::new("test").with_tick_size(dec!(0.000000000000000001));
+        let events = order_book.process(Command::Buy {
+            quantity: 5,
+            price: dec!(2.75),
+            time_in_force: TimeInForce::Day,
+            post_only: None,
+            owner: None,
+        });
+        save(&db, "test", &events).await;
+        let events = order_book.process(Command::Sell {
+            quantity: 10,
+            price: dec!(4.5),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
+        });
+        save(&db, "test", &events).await;
+
+        let events = order_book.process(Command::BuyStop {
+            quantity: 1,
+            trigger_price: dec!(5),
+            limit_price: Some(dec!(5.500000000000000001)),
+            time_in_force: TimeInForce::GoodTillCancel,
+        });
+        save(&db, "test", &events).await;
+        let events = order_book.process(Command::MarketBuy { quantity: 1 });
+        save(&db, "test", &events).await;
+        let events = order_book.process(Command::SellIceberg {
+            quantity: 6,
+            display_quantity: 2,
+            price: dec!(6),
+            time_in_force: TimeInForce::GoodTillCancel,
+        });
+        save(&db, "test", &events).await;
+
+        let snapshot = Snapshot {
+            ts: Utc::now(),
+            last_event_id: last_event_id(&db, "test").await.unwrap(),
+            state: order_book.state(),
+        };
+        save_snapshot(&db, "test", &snapshot).await.unwrap();
+
+        let events = order_book.process(Command::Sell {
+            quantity: 2,
+            price: dec!(2.75),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
+        });
+        save(&db, "test", &events).await;
+        let id = order_book.state().sell[0].id;
+        let events = order_book.process(Command::Cancel { id, side: None });
+        save(&db, "test", &events).await;
+        let events = order_book.process(Command::MarketBuy { quantity: 2 });
+        save(&db, "test", &events).await;
+
+        let snapshot = load_latest_snapshot(&db, "test").await.unwrap().unwrap();
+        assert_eq!(snapshot.last_event_id, 6);
+        assert_eq!(snapshot.state.stop.len(), 1);
+        assert_eq!(
+            snapshot.state.stop[0].price.to_string(),
+            "5.500000000000000001"
+        );
+        assert_eq!(snapshot.state.last_price, Some(dec!(4.5)));
+        let iceberg = snapshot
+            .state
+            .sell
+            .iter()
+            .find(|order| order.price == dec!(6));
+        assert_eq!(iceberg.map(|order| order.reserve_quantity), Some(4));
+        let orders = snapshot.state.buy.iter().chain(snapshot.state.sell.iter());
+        let orders = orders.chain(snapshot.state.stop.iter());
+        let events = load_events(&db, "test", snapshot.last_event_id, orders.cloned())
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 8);
+        let restored = OrderBook::restore("test", snapshot.state, events);
+        assert_eq!(restored.state(), order_book.state());
+    }
+
+    #[tokio::test]
+    async fn test_saved_events_are_logged_in_order() {
+        let db = in_memory_db().await;
+        let mut order_book = OrderBook::new("test");
+        let events = order_book.process(Command::Buy {
+            quantity: 5,
+            price: dec!(2.5),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: Some("alice".to_owned()),
+        });
+        let accepted = save(&db, "test", &events).await;
+        let events = order_book.process(Command::Sell {
+            quantity: 2,
+            price: dec!(2),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
+        });
+        let filled = save(&db, "test", &events).await;
+        let events = order_book.process(Command::Cancel {
+            id: Uuid::new_v4(),
+            side: None,
+        });
+        assert!(save(&db, "test", &events).await.is_empty());
+
+        assert_eq!(accepted.len(), 1);
+        assert_eq!(accepted[0].id, 1);
+        assert_eq!(accepted[0].sequence, 1);
+        assert_eq!(accepted[0].event_type, "buy");
+        assert_eq!(accepted[0].order_price, Some(dec!(2.5)));
+        assert_eq!(accepted[0].owner.as_deref(), Some("alice"));
+        let event_types: Vec<&str> = filled.iter().map(|e| e.event_type.as_str()).collect();
+        assert_eq!(event_types, vec!["sell", "fill"]);
+        let sequences: Vec<u64> = filled.iter().map(|e| e.sequence).collect();
+        assert_eq!(sequences, vec![2, 3]);
+        assert_eq!(filled[1].counterpart_id, Some(accepted[0].order_id));
+
+        let logged = load_logged_events(&db, "test", 0, 10).await.unwrap();
+        assert_eq!(logged, [accepted, filled.clone()].concat());
+        let logged = load_logged_events(&db, "test", 1, 1).await.unwrap();
+        assert_eq!(logged, filled[..1]);
+    }
+
+    #[tokio::test]
+    async fn test_logged_events_never_show_owners_or_the_reserve_of_iceberg_orders() {
+        let db = in_memory_db().await;
+        let mut order_book = OrderBook::new("test");
+        let events = order_book.process(Command::SellIceberg {
+            quantity: 7,
+            display_quantity: 2,
+            price: dec!(5),
+            time_in_force: TimeInForce::GoodTillCancel,
+        });
+        let id = save(&db, "test", &events).await[0].order_id;
+        let events = order_book.process(Command::Buy {
+            quantity: 2,
+            price: dec!(5),
+            time_in_force: TimeInForce::ImmediateOrCancel,
+            post_only: None,
+            owner: Some("alice".to_owned()),
+        });
+        save(&db, "test", &events).await;
+        for (new_quantity, new_price) in [(6, dec!(4)), (5, dec!(4))] {
+            let events = order_book.process(Command::Update {
+                id,
+                side: None,
+                new_quantity,
+                new_price,
+            });
+            save(&db, "test", &events).await;
+        }
+        let events = order_book.process(Command::BuyIceberg {
+            quantity: 7,
+            display_quantity: 2,
+            price: dec!(4),
+            time_in_force: TimeInForce::GoodTillCancel,
+        });
+        save(&db, "test", &events).await;
+
+        let logged = load_logged_events(&db, "test", 0, 20).await.unwrap();
+        let queried = query_events(&db, "test", &EventQuery::default(), 0, 20)
+            .await
+            .unwrap();
+        assert_eq!(queried.events, logged);
+        let event_types: Vec<&str> = logged.iter().map(|e| e.event_type.as_str()).collect();
+        assert_eq!(
+            event_types[..9],
+            [
+                "sell",
+                "buy",
+                "fill",
+                "replenish",
+                "amend",
+                "amend",
+                "buy",
+                "fill",
+                "replenish"
+            ]
+        );
+        for event in &logged {
+            let payload = serde_json::to_value(event).unwrap();
+            assert!(payload.get("reserve_quantity").is_none());
+            assert!(payload.get("owner").is_none());
+            assert!(event.order_quantity.unwrap_or(0) <= 2, "{:?}", event);
+            assert!(event.counterpart_quantity.unwrap_or(0) <= 2, "{:?}", event);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_markets_have_separate_event_logs() {
+        let db = in_memory_db().await;
+        let mut order_books = [OrderBook::new("test"), OrderBook::new("other")];
+        for order_book in &mut order_books {
+            let events = order_book.process(Command::Buy {
+                quantity: 5,
+                price: dec!(2),
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                owner: None,
+            });
+            save(&db, &order_book.ticker, &events).await;
+            let events = order_book.process(Command::Sell {
+                quantity: 2,
+                price: dec!(2),
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                owner: None,
+            });
+            save(&db, &order_book.ticker, &events).await;
+            let snapshot = Snapshot {
+                ts: Utc::now(),
+                last_event_id: last_event_id(&db, &order_book.ticker).await.unwrap(),
+                state: order_book.state(),
+            };
+            save_snapshot(&db, &order_book.ticker, &snapshot)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(last_event_id(&db, "test").await.unwrap(), 3);
+        assert_eq!(last_event_id(&db, "other").await.unwrap(), 6);
+        assert_eq!(last_event_id(&db, "unknown").await.unwrap(), 0);
+        let logged = load_logged_events(&db, "other", 0, 10).await.unwrap();
+        let ids: Vec<(i64, u64)> = logged
+            .iter()
+            .map(|event| (event.id, event.sequence))
+            .collect();
+        assert_eq!(ids, vec![(4, 1), (5, 2), (6, 3)]);
+        assert_eq!(last_sequence(&db, "other").await.unwrap(), 3);
+        assert_eq!(last_sequence(&db, "unknown").await.unwrap(), 0);
+        assert!(logged.iter().all(|event| event.ticker == "other"));
+        assert_eq!(
+            load_trades(&db, "test", Utc::now() - chrono::Duration::hours(1))
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        for order_book in order_books {
+            let snapshot = load_latest_snapshot(&db, &order_book.ticker)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(snapshot.state, order_book.state());
+            let events = load_events(&db, &order_book.ticker, 0, []).await.unwrap();
+            let restored = OrderBook::restore(&order_book.ticker, Default::default(), events);
+            assert_eq!(restored.state(), order_book.state());
+        }
+
+        // A number is never given twice to events of the same market.
+        let mut order_book = OrderBook::new("test");
+        let events = order_book.process(Command::MarketBuy { quantity: 1 });
+        assert!(save_events(&db, "test", 2, &events).await.is_err());
+        assert_eq!(save(&db, "test", &events).await[0].sequence, 4);
+    }
+
+    #[tokio::test]
+    async fn test_events_are_queried_with_filters_page_by_page() {
+        let db = in_memory_db().await;
+        let start = Utc::now();
+        let clock = Rc::new(ManualClock::new(start));
+        let mut order_book = OrderBook::new("test").with_clock(clock.clone());
+        let events = order_book.process(Command::Buy {
+            quantity: 5,
+            price: dec!(2),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
+        });
+        let buy = save(&db, "test", &events).await[0].order_id;
+        clock.advance(chrono::Duration::minutes(1));
+        let events = order_book.process(Command::Sell {
+            quantity: 2,
+            price: dec!(2),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
+        });
+        save(&db, "test", &events).await;
+        clock.advance(chrono::Duration::minutes(1));
+        let events = order_book.process(Command::Cancel {
+            id: buy,
+            side: None,
+        });
+        save(&db, "test", &events).await;
+        let mut other = OrderBook::new("other").with_clock(clock.clone());
+        let events = other.process(Command::MarketBuy { quantity: 1 });
+        save(&db, "other", &events).await;
+
+        let sequences = |query: EventQuery, after_sequence, limit| {
+            let db = db.clone();
+            async move {
+                let page = query_events(&db, "test", &query, after_sequence, limit)
+                    .await
+                    .unwrap();
+                let sequences: Vec<u64> = page.events.iter().map(|event| event.sequence).collect();
+                (sequences, page.next_cursor)
+            }
+        };
+        assert_eq!(
+            sequences(EventQuery::default(), 0, 2).await,
+            (vec![1, 2], Some("2".to_owned()))
+        );
+        let after = parse_cursor("2").unwrap();
+        assert_eq!(
+            sequences(EventQuery::default(), after, 2).await,
+            (vec![3, 4], None)
+        );
+        let minutes = |minutes| start + chrono::Duration::minutes(minutes);
+        let query = EventQuery {
+            from: Some(minutes(1)),
+            to: Some(minutes(2)),
+            ..Default::default()
+        };
+        assert_eq!(sequences(query, 0, 10).await, (vec![2, 3], None));
+        let query = EventQuery {
+            event_types: vec![EventType::Fill, EventType::Cancel],
+            ..Default::default()
+        };
+        assert_eq!(sequences(query, 0, 10).await, (vec![3, 4], None));
+        let query = EventQuery {
+            order_id: Some(buy),
+            ..Default::default()
+        };
+        assert_eq!(sequences(query, 0, 10).await, (vec![1, 3, 4], None));
+        let query = EventQuery {
+            order_id: Some(buy),
+            event_types: vec![EventType::Fill],
+            ..Default::default()
+        };
+        assert_eq!(sequences(query, 0, 10).await, (vec![3], None));
+        let query = EventQuery {
+            from_sequence: Some(2),
+            to_sequence: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(
+            sequences(query.clone(), 0, 1).await,
+            (vec![2], Some("2".to_owned()))
+        );
+        assert_eq!(sequences(query, 2, 1).await, (vec![3], None));
+    }
+}
@@ -1,58 +1,119 @@
 use crate::{
+    actor::{
+        Channels, DepthUpdate, MarketDataSnapshot, MarketDataUpdate, SequencedEvent, SnapshotInfo,
+        Subscription, Ticker,
+    },
     database,
-    order_book::{Event, OrderBookState},
+    order_book::{
+        Event, InstrumentRules, OrderBookDepth, OrderBookState, OrderType, PostOnly, RejectionCode,
+        TimeInForce, Trade,
+    },
+    registry::Market,
     AppContext, Error, Result,
 };
 
 use axum::{
-    debug_handler, extract::Path, http::StatusCode, response::IntoResponse, response::Response,
-    routing::get, routing::patch, routing::post, Extension, Json, Router,
+    debug_handler,
+    extract::{
+        rejection::JsonRejection,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        FromRequest, Path, Query,
+    },
+    http::HeaderMap,
+    http::StatusCode,
+    response::sse::{self, KeepAlive, Sse},
+    response::IntoResponse,
+    response::Response,
+    routing::delete,
+    routing::get,
+    routing::patch,
+    routing::post,
+    Extension, Json, Router,
 };
 use chrono::{DateTime, Utc};
+use futures::Stream;
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
 use uuid::Uuid;
 
-#[derive(serde::Serialize)]
+/// The body of every error response, `code` is machine readable and
+/// `message` for humans.
+#[derive(Serialize)]
 struct ErrorPayload {
+    code: ErrorCode,
+    message: String,
     ts: DateTime<Utc>,
-    reason: String,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ErrorCode {
+    Rejection(RejectionCode),
+    Error(String),
 }
 
 fn status_code(error: &Error) -> StatusCode {
     match error {
-        Error::EventRejection { .. } => StatusCode::BAD_REQUEST,
+        Error::EventRejection {
+            code: RejectionCode::OrderNotFound,
+            ..
+        } => StatusCode::NOT_FOUND,
+        Error::EventRejection { .. } | Error::InvalidMarket { .. } => {
+            StatusCode::UNPROCESSABLE_ENTITY
+        }
+        Error::InvalidRequest { .. } => StatusCode::BAD_REQUEST,
+        Error::MarketAlreadyListed { .. } => StatusCode::CONFLICT,
+        Error::MarketNotFound { .. } => StatusCode::NOT_FOUND,
         _ => StatusCode::INTERNAL_SERVER_ERROR,
     }
 }
 
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
-        match self {
-            Self::EventRejection { ts, reason } => {
-                let t = (StatusCode::BAD_REQUEST, Json(ErrorPayload { ts, reason }));
-                return t.into_response();
-            }
-            Self::ApplicationError { reason } => {
-                let t = (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(ErrorPayload {
-                        ts: Utc::now(),
-                        reason,
-                    }),
-                );
-                return t.into_response();
-            }
-            Self::Database(ref e) => {
+        let status = status_code(&self);
+        let message = match &self {
+            Self::EventRejection { reason, .. }
+            | Self::InvalidRequest { reason }
+            | Self::InvalidMarket { reason }
+            | Self::ApplicationError { reason } => reason.clone(),
+            Self::MarketAlreadyListed { ticker } => format!("Market {} already listed", ticker),
+            Self::MarketNotFound { ticker } => format!("Unknown market {}", ticker),
+            Self::Database(e) => {
                 tracing::error!("Database error: {:?}", e);
+                "Internal server error".to_owned()
             }
-            Self::Anyhow(ref e) => {
+            Self::Anyhow(e) => {
                 tracing::error!("Generic error: {:?}", e);
+                "Internal server error".to_owned()
             }
+            Self::Migration(_) => "Internal server error".to_owned(),
+        };
+        let payload = match self {
+            Self::EventRejection { ts, code, .. } => ErrorPayload {
+                code: ErrorCode::Rejection(code),
+                message,
+                ts,
+            },
+            _ => ErrorPayload {
+                code: ErrorCode::Error(self.to_string()),
+                message,
+                ts: Utc::now(),
+            },
+        };
+        (status, Json(payload)).into_response()
+    }
+}
 
-            _ => (),
-        }
-        (status_code(&self), self.to_string()).into_response()
+/// A JSON request body, malformed bodies are answered with the same error
+/// payload as every other error.
+#[derive(FromRequest)]
+#[from_request(via(Json), rejection(Error))]
+struct JsonBody<T>(T);
+
+impl From<JsonRejection> for Error {
+    fn from(rejection: JsonRejection) -> Self {
+        Error::invalid_request(rejection.body_text())
     }
 }
 
@@ -67,99 +128,935 @@ pub fn routes() -> Router {
 }
 
 fn routes_v1() -> Router {
-    Router::new().nest("/v1", order_book_routes())
+    Router::new().nest("/v1", market_routes().merge(admin_routes()))
+}
+
+fn admin_routes() -> Router {
+    // POST v1/admin/markets lists a market, with its tick size, lot size and optional min/max price
+    // DELETE v1/admin/markets/{ticker} delists a market, canceling all of its orders
+    // POST v1/admin/markets/{ticker}/snapshot takes a snapshot of the order book state of a market
+    Router::new()
+        .route("/admin/markets", post(post_market))
+        .route("/admin/markets/:ticker", delete(delete_market))
+        .route("/admin/markets/:ticker/snapshot", post(post_snapshot))
+}
+
+fn market_routes() -> Router {
+    // GET v1/markets returns the markets traded
+    Router::new()
+        .route("/markets", get(get_markets))
+        .nest("/markets/:ticker", order_book_routes())
 }
 
 fn order_book_routes() -> Router {
-    // GET v1/order-book/ returns the state of buy/sell book
-    // POST v1/order-book/buy submit a buy order (returns Uuid of the order)
-    // POST v1/order-book/sell submit a sell order (returns Uuid of the order)
-    // PATCH v1/order-book/buy/{uuid} updates a buy order with new price and quantity
-    // PATCH v1/order-book/sell/{uuid} updates a sell order with new price and quantity
-    // DELETE v1/order-book/buy/{uuid} cancel a buy order
-    // DELETE v1/order-book/sell/{uuid} cancel a sell order
+    // GET v1/markets/{ticker}/order-book/ returns the state of buy/sell book
+    // GET v1/markets/{ticker}/order-book/ticker returns best bid/ask, spread, mid price, last trade and 24h volume
+    // GET v1/markets/{ticker}/order-book/depth?levels={n} returns the best n price levels of buy/sell book, 10 by default
+    // GET v1/markets/{ticker}/order-book/events?after_sequence={n}&limit={m} returns a page of the persisted events numbered after n, 100 at most by default,
+    //   filtered by from/to time, event_type, order_id and from_sequence/to_sequence, the next page after its cursor
+    // GET v1/markets/{ticker}/order-book/feed?after_sequence={n} streams the persisted event log as Server-Sent Events, resuming after Last-Event-ID or n
+    // GET v1/markets/{ticker}/order-book/stream?channels={trades,depth,orders} streams a snapshot then sequenced updates over a WebSocket
+    // POST v1/markets/{ticker}/order-book/buy submit a buy order (returns Uuid of the order)
+    // POST v1/markets/{ticker}/order-book/sell submit a sell order (returns Uuid of the order)
+    // POST v1/markets/{ticker}/order-book/buy/market submit a market buy order, any unfilled quantity is canceled
+    // POST v1/markets/{ticker}/order-book/sell/market submit a market sell order, any unfilled quantity is canceled
+    // POST v1/markets/{ticker}/order-book/buy/stop submit a buy stop order, triggered at or above the trigger price
+    // POST v1/markets/{ticker}/order-book/sell/stop submit a sell stop order, triggered at or below the trigger price
+    // POST v1/markets/{ticker}/order-book/buy/iceberg submit a buy iceberg order, showing only its display quantity
+    // POST v1/markets/{ticker}/order-book/sell/iceberg submit a sell iceberg order, showing only its display quantity
+    // PATCH v1/markets/{ticker}/order-book/buy/{uuid} amends a buy order with new price and quantity, keeping its id
+    // PATCH v1/markets/{ticker}/order-book/sell/{uuid} amends a sell order with new price and quantity, keeping its id
+    // DELETE v1/markets/{ticker}/order-book/buy/{uuid} cancel a buy order
+    // DELETE v1/markets/{ticker}/order-book/sell/{uuid} cancel a sell order
+    // PATCH v1/markets/{ticker}/order-book/orders/{uuid} amends an order whatever its side
+    // DELETE v1/markets/{ticker}/order-book/orders/{uuid} cancel an order whatever its side
     Router::new()
         .route("/order-book", get(get_order_book))
+        .route("/order-book/ticker", get(get_ticker))
+        .route("/order-book/depth", get(get_depth))
+        .route("/order-book/events", get(get_events))
+        .route("/order-book/feed", get(get_feed))
+        .route("/order-book/stream", get(get_stream))
         .route("/order-book/sell", post(post_sell))
+        .route("/order-book/sell/market", post(post_market_sell))
+        .route("/order-book/sell/stop", post(post_sell_stop))
+        .route("/order-book/sell/iceberg", post(post_sell_iceberg))
         .route(
             "/order-book/sell/:id",
             patch(patch_sell).delete(delete_sell),
         )
         .route("/order-book/buy", post(post_buy))
+        .route("/order-book/buy/market", post(post_market_buy))
+        .route("/order-book/buy/stop", post(post_buy_stop))
+        .route("/order-book/buy/iceberg", post(post_buy_iceberg))
         .route("/order-book/buy/:id", patch(patch_buy).delete(delete_buy))
+        .route(
+            "/order-book/orders/:id",
+            patch(patch_order).delete(delete_order),
+        )
 }
 
+/// The events emitted by a command, numbered in the event log of the market,
+/// with the trades of its fills.
 #[derive(Serialize)]
 struct EventsResponse {
-    events: Vec<Event>,
+    events: Vec<SequencedEvent>,
+    trades: Vec<Trade>,
+}
+
+/// The events of a command, unless rejected by the order book.
+impl TryFrom<Vec<SequencedEvent>> for EventsResponse {
+    type Error = Error;
+
+    /// Only a rejection of the submitted command itself is an error, it
+    /// comes right after the orders which expired before the command.
+    /// Orders the command triggered may still be rejected after it was
+    /// accepted.
+    fn try_from(events: Vec<SequencedEvent>) -> Result<Self> {
+        let command_event = events
+            .iter()
+            .find(|event| !matches!(event.event, Event::Expired { .. }));
+        if let Some(SequencedEvent {
+            event: Event::Rejected { ts, code, reason },
+            ..
+        }) = command_event
+        {
+            return Err(Error::event_rejection(*ts, *code, reason));
+        }
+        let trades = events
+            .iter()
+            .filter_map(|event| match &event.event {
+                Event::Filled { trade, .. } => Some(trade.clone()),
+                _ => None,
+            })
+            .collect();
+        Ok(EventsResponse { events, trades })
+    }
 }
 
 #[derive(Deserialize)]
 struct OrderRequest {
     quantity: u32,
     price: Decimal,
+    #[serde(default)]
+    time_in_force: TimeInForce,
+    #[serde(default)]
+    post_only: Option<PostOnly>,
+    #[serde(default)]
+    owner: Option<String>,
+}
+
+/// Without a tick size the market takes the configured one.
+#[derive(Deserialize)]
+struct MarketRequest {
+    ticker: String,
+    tick_size: Option<Decimal>,
+    #[serde(default = "default_lot_size")]
+    lot_size: u32,
+    min_quantity: Option<u32>,
+    max_quantity: Option<u32>,
+    min_notional: Option<Decimal>,
+    min_price: Option<Decimal>,
+    max_price: Option<Decimal>,
+    price_band: Option<Decimal>,
+}
+
+fn default_lot_size() -> u32 {
+    1
+}
+
+#[derive(Deserialize)]
+struct DepthQuery {
+    #[serde(default = "default_depth_levels")]
+    levels: usize,
+}
+
+fn default_depth_levels() -> usize {
+    10
+}
+
+/// The events numbered after `after_sequence`, from the first one by default,
+/// or after the `cursor` of the previous page, filtered by time range,
+/// comma separated event types, order and sequence range.
+#[derive(Deserialize)]
+struct EventsQuery {
+    #[serde(default)]
+    after_sequence: u64,
+    #[serde(default = "default_events_limit")]
+    limit: u32,
+    cursor: Option<String>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    event_type: Option<String>,
+    order_id: Option<Uuid>,
+    from_sequence: Option<u64>,
+    to_sequence: Option<u64>,
+}
+
+impl EventsQuery {
+    /// The query of the event log and the sequence the page starts after.
+    fn query(&self) -> Result<(database::EventQuery, u64)> {
+        if self.limit == 0 || self.limit as i64 > FEED_PAGE_SIZE {
+            return Err(Error::invalid_request(format!(
+                "Limit must be between 1 and {}",
+                FEED_PAGE_SIZE
+            )));
+        }
+        let after_sequence = match &self.cursor {
+            Some(cursor) => database::parse_cursor(cursor)
+                .ok_or_else(|| Error::invalid_request(format!("Invalid cursor {}", cursor)))?
+                .max(self.after_sequence),
+            None => self.after_sequence,
+        };
+        let mut event_types = vec![];
+        for name in self.event_type.iter().flat_map(|names| names.split(',')) {
+            let name = name.trim();
+            let event_type = database::EventType::parse(name)
+                .ok_or_else(|| Error::invalid_request(format!("Unknown event type {}", name)))?;
+            event_types.push(event_type);
+        }
+        let query = database::EventQuery {
+            from: self.from,
+            to: self.to,
+            event_types,
+            order_id: self.order_id,
+            from_sequence: self.from_sequence,
+            to_sequence: self.to_sequence,
+        };
+        Ok((query, after_sequence))
+    }
+}
+
+fn default_events_limit() -> u32 {
+    100
+}
+
+/// Where to start the feed when not resuming it.
+#[derive(Deserialize)]
+struct FeedQuery {
+    after_sequence: Option<u64>,
+}
+
+/// Comma separated channels, all of them by default.
+#[derive(Deserialize)]
+struct StreamQuery {
+    channels: Option<String>,
+}
+
+impl StreamQuery {
+    fn channels(&self) -> Result<Channels> {
+        let Some(names) = &self.channels else {
+            return Ok(Channels::default());
+        };
+        let mut channels = Channels {
+            trades: false,
+            depth: false,
+            orders: false,
+        };
+        for name in names.split(',').map(str::trim) {
+            match name {
+                "trades" => channels.trades = true,
+                "depth" => channels.depth = true,
+                "orders" => channels.orders = true,
+                _ => return Err(Error::invalid_request(format!("Unknown channel {}", name))),
+            }
+        }
+        Ok(channels)
+    }
+}
+
+/// The messages sent to stream subscribers, the first one is a snapshot and
+/// every following update has the next sequence number.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamMessage<'a> {
+    Snapshot(&'a MarketDataSnapshot),
+    Update {
+        ticker: &'a str,
+        sequence: u64,
+        ts: DateTime<Utc>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        trades: Option<&'a [Trade]>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        depth: Option<&'a [DepthUpdate]>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        orders: Option<&'a [SequencedEvent]>,
+    },
+    /// The subscriber fell behind and missed updates, the stream is closed.
+    Lagged {
+        skipped: u64,
+    },
+}
+
+impl<'a> StreamMessage<'a> {
+    fn update(update: &'a MarketDataUpdate, channels: Channels) -> Self {
+        StreamMessage::Update {
+            ticker: &update.ticker,
+            sequence: update.sequence,
+            ts: update.ts,
+            trades: channels.trades.then_some(&update.trades[..]),
+            depth: channels.depth.then_some(&update.depth[..]),
+            orders: channels.orders.then_some(&update.orders[..]),
+        }
+    }
+
+    fn into_message(self) -> Message {
+        Message::Text(serde_json::to_string(&self).expect("Stream messages are serializable"))
+    }
+}
+
+#[derive(Deserialize)]
+struct MarketOrderRequest {
+    quantity: u32,
+}
+
+/// Without a limit price the stop order becomes a market order once
+/// triggered.
+#[derive(Deserialize)]
+struct StopOrderRequest {
+    quantity: u32,
+    trigger_price: Decimal,
+    limit_price: Option<Decimal>,
+    #[serde(default)]
+    time_in_force: TimeInForce,
+}
+
+/// Only `display_quantity` of the order is shown on the book at a time.
+#[derive(Deserialize)]
+struct IcebergOrderRequest {
+    quantity: u32,
+    display_quantity: u32,
+    price: Decimal,
+    #[serde(default)]
+    time_in_force: TimeInForce,
+}
+
+#[debug_handler()]
+async fn get_markets(Extension(app_context): Extension<AppContext>) -> Json<Vec<Market>> {
+    Json(app_context.markets.markets())
 }
 
 #[debug_handler()]
 async fn get_order_book(
     Extension(app_context): Extension<AppContext>,
+    Path(ticker): Path<String>,
 ) -> Result<Json<OrderBookState>> {
-    let state = app_context.actor_client.get_order_book().await?;
+    let state = app_context.markets.get(&ticker)?.get_order_book().await?;
     Ok(Json(state))
 }
 
+#[debug_handler()]
+async fn get_ticker(
+    Extension(app_context): Extension<AppContext>,
+    Path(ticker): Path<String>,
+) -> Result<Json<Ticker>> {
+    let ticker = app_context.markets.get(&ticker)?.ticker().await?;
+    Ok(Json(ticker))
+}
+
+#[debug_handler()]
+async fn get_depth(
+    Extension(app_context): Extension<AppContext>,
+    Path(ticker): Path<String>,
+    Query(DepthQuery { levels }): Query<DepthQuery>,
+) -> Result<Json<OrderBookDepth>> {
+    let depth = app_context.markets.get(&ticker)?.get_depth(levels).await?;
+    Ok(Json(depth))
+}
+
+/// How many persisted events are read at once when resuming the feed, and
+/// returned at most by a query.
+const FEED_PAGE_SIZE: i64 = 500;
+
+#[debug_handler()]
+async fn get_events(
+    Extension(app_context): Extension<AppContext>,
+    Path(ticker): Path<String>,
+    Query(query): Query<EventsQuery>,
+) -> Result<Json<database::EventPage>> {
+    if !database::market_exists(&app_context.db, &ticker).await? {
+        return Err(Error::MarketNotFound { ticker });
+    }
+    let limit = query.limit as i64;
+    let (query, after_sequence) = query.query()?;
+    let page =
+        database::query_events(&app_context.db, &ticker, &query, after_sequence, limit).await?;
+    Ok(Json(page))
+}
+
+/// Streams the events as they are persisted, preceded by the ones numbered
+/// after the `Last-Event-ID` header when resuming, or after the
+/// `after_sequence` query parameter. The stream ends when the subscriber
+/// falls behind, reconnecting resumes it from the event log.
+#[debug_handler()]
+async fn get_feed(
+    Extension(app_context): Extension<AppContext>,
+    Path(ticker): Path<String>,
+    Query(FeedQuery { after_sequence }): Query<FeedQuery>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<sse::Event, serde_json::Error>>>> {
+    let resume_after = match headers.get("last-event-id") {
+        Some(value) => Some(
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| value.parse::<u64>().ok())
+                .ok_or_else(|| Error::invalid_request("Invalid Last-Event-ID"))?,
+        ),
+        None => after_sequence,
+    };
+    let mut tail = app_context.markets.get(&ticker)?.tail().await?;
+    let db = app_context.db;
+    let stream = async_stream::stream! {
+        if let Some(mut after) = resume_after {
+            'backlog: while after < tail.last_sequence {
+                let events = match database::load_logged_events(&db, &ticker, after, FEED_PAGE_SIZE).await {
+                    Ok(events) => events,
+                    Err(error) => {
+                        tracing::error!("Fail to load the event log, error={}", error);
+                        return;
+                    }
+                };
+                if events.is_empty() {
+                    break;
+                }
+                for event in events {
+                    if event.sequence > tail.last_sequence {
+                        break 'backlog;
+                    }
+                    after = event.sequence;
+                    yield feed_event(&event);
+                }
+            }
+        }
+        loop {
+            match tail.events.recv().await {
+                Ok(event) => yield feed_event(&event),
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Feed subscriber lagged, skipped={}", skipped);
+                    break;
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    };
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+fn feed_event(event: &database::LoggedEvent) -> Result<sse::Event, serde_json::Error> {
+    sse::Event::default()
+        .id(event.sequence.to_string())
+        .event(&event.event_type)
+        .json_data(event)
+}
+
+#[debug_handler()]
+async fn get_stream(
+    Extension(app_context): Extension<AppContext>,
+    Path(ticker): Path<String>,
+    Query(query): Query<StreamQuery>,
+    ws: WebSocketUpgrade,
+) -> Result<Response> {
+    let channels = query.channels()?;
+    let subscription = app_context
+        .markets
+        .get(&ticker)?
+        .subscribe(channels)
+        .await?;
+    Ok(ws.on_upgrade(move |socket| stream(socket, channels, subscription)))
+}
+
+async fn stream(mut socket: WebSocket, channels: Channels, mut subscription: Subscription) {
+    let snapshot = StreamMessage::Snapshot(&subscription.snapshot).into_message();
+    if socket.send(snapshot).await.is_err() {
+        return;
+    }
+    loop {
+        tokio::select! {
+            update = subscription.updates.recv() => {
+                let message = match update {
+                    Ok(update) => StreamMessage::update(&update, channels).into_message(),
+                    Err(RecvError::Lagged(skipped)) => {
+                        let _ = socket.send(StreamMessage::Lagged { skipped }.into_message()).await;
+                        break;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                if socket.send(message).await.is_err() {
+                    break;
+                }
+            }
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => (),
+            },
+        }
+    }
+    let _ = socket.close().await;
+}
+
 #[debug_handler()]
 async fn post_buy(
     Extension(app_context): Extension<AppContext>,
-    Json(OrderRequest { quantity, price }): Json<OrderRequest>,
+    Path(ticker): Path<String>,
+    JsonBody(OrderRequest {
+        quantity,
+        price,
+        time_in_force,
+        post_only,
+        owner,
+    }): JsonBody<OrderRequest>,
+) -> Result<Json<EventsResponse>> {
+    let events = app_context
+        .markets
+        .get(&ticker)?
+        .buy(quantity, price, time_in_force, post_only, owner)
+        .await?;
+    Ok(Json(events.try_into()?))
+}
+
+#[debug_handler()]
+async fn post_market_buy(
+    Extension(app_context): Extension<AppContext>,
+    Path(ticker): Path<String>,
+    JsonBody(MarketOrderRequest { quantity }): JsonBody<MarketOrderRequest>,
+) -> Result<Json<EventsResponse>> {
+    let events = app_context
+        .markets
+        .get(&ticker)?
+        .market_buy(quantity)
+        .await?;
+    Ok(Json(events.try_into()?))
+}
+
+#[debug_handler()]
+async fn post_buy_stop(
+    Extension(app_context): Extension<AppContext>,
+    Path(ticker): Path<String>,
+    JsonBody(StopOrderRequest {
+        quantity,
+        trigger_price,
+        limit_price,
+        time_in_force,
+    }): JsonBody<StopOrderRequest>,
 ) -> Result<Json<EventsResponse>> {
-    let events = app_context.actor_client.buy(quantity, price).await?;
-    Ok(Json(EventsResponse { events }))
+    let events = app_context
+        .markets
+        .get(&ticker)?
+        .buy_stop(quantity, trigger_price, limit_price, time_in_force)
+        .await?;
+    Ok(Json(events.try_into()?))
+}
+
+#[debug_handler()]
+async fn post_buy_iceberg(
+    Extension(app_context): Extension<AppContext>,
+    Path(ticker): Path<String>,
+    JsonBody(IcebergOrderRequest {
+        quantity,
+        display_quantity,
+        price,
+        time_in_force,
+    }): JsonBody<IcebergOrderRequest>,
+) -> Result<Json<EventsResponse>> {
+    let events = app_context
+        .markets
+        .get(&ticker)?
+        .buy_iceberg(quantity, display_quantity, price, time_in_force)
+        .await?;
+    Ok(Json(events.try_into()?))
 }
 
 #[debug_handler()]
 async fn patch_buy(
     Extension(app_context): Extension<AppContext>,
-    Path(id): Path<Uuid>,
-    Json(OrderRequest { quantity, price }): Json<OrderRequest>,
+    Path((ticker, id)): Path<(String, Uuid)>,
+    JsonBody(OrderRequest {
+        quantity, price, ..
+    }): JsonBody<OrderRequest>,
 ) -> Result<Json<EventsResponse>> {
-    let events = app_context.actor_client.update(id, quantity, price).await?;
-    Ok(Json(EventsResponse { events }))
+    let events = app_context
+        .markets
+        .get(&ticker)?
+        .update(id, Some(OrderType::Buy), quantity, price)
+        .await?;
+    Ok(Json(events.try_into()?))
 }
 
 #[debug_handler()]
 async fn delete_buy(
     Extension(app_context): Extension<AppContext>,
-    Path(id): Path<Uuid>,
+    Path((ticker, id)): Path<(String, Uuid)>,
 ) -> Result<Json<EventsResponse>> {
-    let events = app_context.actor_client.cancel(id).await?;
-    Ok(Json(EventsResponse { events }))
+    let events = app_context
+        .markets
+        .get(&ticker)?
+        .cancel(id, Some(OrderType::Buy))
+        .await?;
+    Ok(Json(events.try_into()?))
 }
 
 #[debug_handler()]
 async fn post_sell(
     Extension(app_context): Extension<AppContext>,
-    Json(OrderRequest { quantity, price }): Json<OrderRequest>,
+    Path(ticker): Path<String>,
+    JsonBody(OrderRequest {
+        quantity,
+        price,
+        time_in_force,
+        post_only,
+        owner,
+    }): JsonBody<OrderRequest>,
+) -> Result<Json<EventsResponse>> {
+    let events = app_context
+        .markets
+        .get(&ticker)?
+        .sell(quantity, price, time_in_force, post_only, owner)
+        .await?;
+    Ok(Json(events.try_into()?))
+}
+
+#[debug_handler()]
+async fn post_market_sell(
+    Extension(app_context): Extension<AppContext>,
+    Path(ticker): Path<String>,
+    JsonBody(MarketOrderRequest { quantity }): JsonBody<MarketOrderRequest>,
+) -> Result<Json<EventsResponse>> {
+    let events = app_context
+        .markets
+        .get(&ticker)?
+        .market_sell(quantity)
+        .await?;
+    Ok(Json(events.try_into()?))
+}
+
+#[debug_handler()]
+async fn post_sell_stop(
+    Extension(app_context): Extension<AppContext>,
+    Path(ticker): Path<String>,
+    JsonBody(StopOrderRequest {
+        quantity,
+        trigger_price,
+        limit_price,
+        time_in_force,
+    }): JsonBody<StopOrderRequest>,
 ) -> Result<Json<EventsResponse>> {
-    let events = app_context.actor_client.sell(quantity, price).await?;
-    Ok(Json(EventsResponse { events }))
+    let events = app_context
+        .markets
+        .get(&ticker)?
+        .sell_stop(quantity, trigger_price, limit_price, time_in_force)
+        .await?;
+    Ok(Json(events.try_into()?))
+}
+
+#[debug_handler()]
+async fn post_sell_iceberg(
+    Extension(app_context): Extension<AppContext>,
+    Path(ticker): Path<String>,
+    JsonBody(IcebergOrderRequest {
+        quantity,
+        display_quantity,
+        price,
+        time_in_force,
+    }): JsonBody<IcebergOrderRequest>,
+) -> Result<Json<EventsResponse>> {
+    let events = app_context
+        .markets
+        .get(&ticker)?
+        .sell_iceberg(quantity, display_quantity, price, time_in_force)
+        .await?;
+    Ok(Json(events.try_into()?))
 }
 
 #[debug_handler()]
 async fn patch_sell(
     Extension(app_context): Extension<AppContext>,
-    Path(id): Path<Uuid>,
-    Json(OrderRequest { quantity, price }): Json<OrderRequest>,
+    Path((ticker, id)): Path<(String, Uuid)>,
+    JsonBody(OrderRequest {
+        quantity, price, ..
+    }): JsonBody<OrderRequest>,
 ) -> Result<Json<EventsResponse>> {
-    let events = app_context.actor_client.update(id, quantity, price).await?;
-    Ok(Json(EventsResponse { events }))
+    let events = app_context
+        .markets
+        .get(&ticker)?
+        .update(id, Some(OrderType::Sell), quantity, price)
+        .await?;
+    Ok(Json(events.try_into()?))
 }
 
 #[debug_handler()]
 async fn delete_sell(
     Extension(app_context): Extension<AppContext>,
-    Path(id): Path<Uuid>,
+    Path((ticker, id)): Path<(String, Uuid)>,
+) -> Result<Json<EventsResponse>> {
+    let events = app_context
+        .markets
+        .get(&ticker)?
+        .cancel(id, Some(OrderType::Sell))
+        .await?;
+    Ok(Json(events.try_into()?))
+}
+
+#[debug_handler()]
+async fn patch_order(
+    Extension(app_context): Extension<AppContext>,
+    Path((ticker, id)): Path<(String, Uuid)>,
+    JsonBody(OrderRequest {
+        quantity, price, ..
+    }): JsonBody<OrderRequest>,
+) -> Result<Json<EventsResponse>> {
+    let events = app_context
+        .markets
+        .get(&ticker)?
+        .update(id, None, quantity, price)
+        .await?;
+    Ok(Json(events.try_into()?))
+}
+
+#[debug_handler()]
+async fn delete_order(
+    Extension(app_context): Extension<AppContext>,
+    Path((ticker, id)): Path<(String, Uuid)>,
+) -> Result<Json<EventsResponse>> {
+    let events = app_context.markets.get(&ticker)?.cancel(id, None).await?;
+    Ok(Json(events.try_into()?))
+}
+
+#[debug_handler()]
+async fn post_snapshot(
+    Extension(app_context): Extension<AppContext>,
+    Path(ticker): Path<String>,
+) -> Result<Json<SnapshotInfo>> {
+    let snapshot = app_context.markets.get(&ticker)?.snapshot().await?;
+    Ok(Json(snapshot))
+}
+
+#[debug_handler()]
+async fn post_market(
+    Extension(app_context): Extension<AppContext>,
+    JsonBody(MarketRequest {
+        ticker,
+        tick_size,
+        lot_size,
+        min_quantity,
+        max_quantity,
+        min_notional,
+        min_price,
+        max_price,
+        price_band,
+    }): JsonBody<MarketRequest>,
+) -> Result<(StatusCode, Json<Market>)> {
+    let market = Market {
+        ticker,
+        tick_size: tick_size.unwrap_or(app_context.config.tick_size),
+        rules: InstrumentRules {
+            lot_size,
+            min_quantity,
+            max_quantity,
+            min_notional,
+            min_price,
+            max_price,
+            price_band,
+        },
+    };
+    let market = app_context.markets.list(market).await?;
+    Ok((StatusCode::CREATED, Json(market)))
+}
+
+#[debug_handler()]
+async fn delete_market(
+    Extension(app_context): Extension<AppContext>,
+    Path(ticker): Path<String>,
 ) -> Result<Json<EventsResponse>> {
-    let events = app_context.actor_client.cancel(id).await?;
-    Ok(Json(EventsResponse { events }))
+    let events = app_context.markets.delist(&ticker).await?;
+    Ok(Json(events.try_into()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use chrono::{Duration, NaiveTime, TimeZone};
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::order_book::{clock::ManualClock, Command, OrderBook};
+
+    #[test]
+    fn test_rejected_events_are_client_errors() {
+        let ts = Utc::now();
+        let rejected = |code| {
+            let events = vec![SequencedEvent {
+                sequence: None,
+                event: Event::Rejected {
+                    ts,
+                    code,
+                    reason: "Rejected".to_owned(),
+                },
+            }];
+            let error = EventsResponse::try_from(events).err().unwrap();
+            assert!(matches!(error, Error::EventRejection { .. }));
+            error.into_response().status()
+        };
+        assert_eq!(
+            rejected(RejectionCode::OrderNotFound),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            rejected(RejectionCode::TickSize),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(
+            rejected(RejectionCode::PostOnlyWouldTakeLiquidity),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+
+        let payload = serde_json::to_value(ErrorPayload {
+            code: ErrorCode::Rejection(RejectionCode::MinNotional),
+            message: "Rejected".to_owned(),
+            ts,
+        })
+        .unwrap();
+        assert_eq!(payload["code"], "min_notional");
+        let payload = serde_json::to_value(ErrorPayload {
+            code: ErrorCode::Error(Error::invalid_request("Invalid").to_string()),
+            message: "Invalid".to_owned(),
+            ts,
+        })
+        .unwrap();
+        assert_eq!(payload["code"], "invalid_request");
+    }
+
+    #[test]
+    fn test_rejected_commands_are_client_errors_after_expiries() {
+        let start = Utc.with_ymd_and_hms(2023, 6, 1, 16, 0, 0).unwrap();
+        let clock = Rc::new(ManualClock::new(start));
+        let mut order_book = OrderBook::new("test")
+            .with_session_end(NaiveTime::from_hms_opt(17, 0, 0).unwrap())
+            .with_clock(clock.clone());
+        order_book.process(Command::Buy {
+            quantity: 5,
+            price: dec!(2),
+            time_in_force: TimeInForce::Day,
+            post_only: None,
+            owner: None,
+        });
+        clock.advance(Duration::hours(2));
+        let events: Vec<SequencedEvent> = order_book
+            .process(Command::Cancel {
+                id: Uuid::new_v4(),
+                side: None,
+            })
+            .into_iter()
+            .map(|event| SequencedEvent {
+                sequence: database::is_logged(&event).then_some(2),
+                event,
+            })
+            .collect();
+        assert!(matches!(
+            events[..],
+            [
+                SequencedEvent {
+                    sequence: Some(2),
+                    event: Event::Expired { .. },
+                },
+                SequencedEvent {
+                    sequence: None,
+                    event: Event::Rejected { .. },
+                },
+            ]
+        ));
+        let error = EventsResponse::try_from(events).err().unwrap();
+        assert_eq!(error.into_response().status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_rejected_triggered_orders_are_answered_with_the_events() {
+        let mut order_book = OrderBook::new("test");
+        let mut sequences = 1..;
+        let mut process = |command| -> Vec<SequencedEvent> {
+            order_book
+                .process(command)
+                .into_iter()
+                .map(|event| SequencedEvent {
+                    sequence: database::is_logged(&event)
+                        .then(|| sequences.next())
+                        .flatten(),
+                    event,
+                })
+                .collect()
+        };
+        process(Command::Sell {
+            quantity: 1,
+            price: dec!(10),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
+        });
+        process(Command::BuyStop {
+            quantity: 5,
+            trigger_price: dec!(10),
+            limit_price: Some(dec!(10)),
+            time_in_force: TimeInForce::FillOrKill,
+        });
+        let events = process(Command::Buy {
+            quantity: 1,
+            price: dec!(10),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
+        });
+        assert!(matches!(
+            events.last().map(|event| &event.event),
+            Some(Event::Rejected { .. })
+        ));
+        let response = EventsResponse::try_from(events).unwrap();
+        assert_eq!(response.trades.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_malformed_bodies_are_invalid_requests() {
+        let request = axum::http::Request::post("/")
+            .header("content-type", "application/json")
+            .body(axum::body::Body::from(r#"{"quantity": "ten"}"#))
+            .unwrap();
+        let error = JsonBody::<OrderRequest>::from_request(request, &())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(error, Error::InvalidRequest { .. }));
+        assert_eq!(error.into_response().status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_events_query_parameters() {
+        let query = |parameters: &str| {
+            let uri = format!("/events?{}", parameters).parse().unwrap();
+            let Query(query) = Query::<EventsQuery>::try_from_uri(&uri).unwrap();
+            query.query()
+        };
+        let (events, after_sequence) = query("").unwrap();
+        assert_eq!(events, database::EventQuery::default());
+        assert_eq!(after_sequence, 0);
+
+        let order_id = Uuid::new_v4();
+        let (events, after_sequence) = query(&format!(
+            "event_type=fill,%20cancel&order_id={}&from=2023-06-01T12:00:00Z\
+             &to_sequence=9&after_sequence=2&cursor=5",
+            order_id
+        ))
+        .unwrap();
+        assert_eq!(
+            events.event_types,
+            vec![database::EventType::Fill, database::EventType::Cancel]
+        );
+        assert_eq!(events.order_id, Some(order_id));
+        assert_eq!(events.from, Some("2023-06-01T12:00:00Z".parse().unwrap()));
+        assert_eq!(events.to_sequence, Some(9));
+        assert_eq!(after_sequence, 5);
+
+        for invalid in ["event_type=bid", "cursor=next", "limit=0", "limit=501"] {
+            assert!(matches!(query(invalid), Err(Error::InvalidRequest { .. })));
+        }
+    }
 }
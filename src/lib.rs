@@ -2,34 +2,106 @@ pub mod actor;
 pub mod database;
 pub mod endpoints;
 pub mod order_book;
+pub mod registry;
 
 use std::sync::Arc;
 
-use actor::Client;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveTime, Utc};
+use order_book::{RejectionCode, SelfTradePrevention};
+use registry::Registry;
+use rust_decimal::Decimal;
 
 #[derive(Clone)]
 pub struct AppContext {
     pub db: sqlx::Pool<sqlx::Sqlite>,
     pub config: Arc<Config>,
-    pub actor_client: Client,
+    pub markets: Registry,
 }
 
 pub struct Config {
     pub database_file: String,
+    pub markets: Vec<String>,
+    pub snapshot_every_events: Option<u64>,
+    pub snapshot_every_seconds: Option<u64>,
+    pub session_end: NaiveTime,
+    pub tick_size: Decimal,
+    pub self_trade_prevention: SelfTradePrevention,
+    pub check_invariants: bool,
 }
 
 impl Config {
     pub fn parse() -> anyhow::Result<Self> {
         let database_file = std::env::var("DATABASE_FILE")?;
-        Ok(Config { database_file })
+        let markets = match optional_env::<String>("MARKETS")? {
+            Some(markets) => markets
+                .split(',')
+                .map(|ticker| ticker.trim().to_owned())
+                .filter(|ticker| !ticker.is_empty())
+                .collect(),
+            None => vec!["vibranium".to_owned()],
+        };
+        let snapshot_every_events = optional_env("SNAPSHOT_EVERY_EVENTS")?;
+        let snapshot_every_seconds = optional_env("SNAPSHOT_EVERY_SECONDS")?;
+        let session_end = optional_env("SESSION_END")?.unwrap_or(NaiveTime::MIN);
+        let tick_size = optional_env("TICK_SIZE")?.unwrap_or(Decimal::new(1, 2));
+        if tick_size <= Decimal::ZERO {
+            anyhow::bail!("TICK_SIZE={} must be positive", tick_size);
+        }
+        let self_trade_prevention =
+            match optional_env::<String>("SELF_TRADE_PREVENTION")?.as_deref() {
+                None | Some("cancel_newest") => SelfTradePrevention::CancelNewest,
+                Some("cancel_oldest") => SelfTradePrevention::CancelOldest,
+                Some("cancel_both") => SelfTradePrevention::CancelBoth,
+                Some("decrement_and_cancel") => SelfTradePrevention::DecrementAndCancel,
+                Some(value) => anyhow::bail!("Unknown SELF_TRADE_PREVENTION={}", value),
+            };
+        let check_invariants = optional_env("CHECK_INVARIANTS")?.unwrap_or(false);
+        Ok(Config {
+            database_file,
+            markets,
+            snapshot_every_events,
+            snapshot_every_seconds,
+            session_end,
+            tick_size,
+            self_trade_prevention,
+            check_invariants,
+        })
+    }
+}
+
+fn optional_env<T>(name: &str) -> anyhow::Result<Option<T>>
+where
+    T: std::str::FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match std::env::var(name) {
+        Ok(value) => Ok(Some(value.parse()?)),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(error) => Err(error.into()),
     }
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
+    /// A command rejected by the order book, `code` tells why.
     #[error("rejection")]
-    EventRejection { ts: DateTime<Utc>, reason: String },
+    EventRejection {
+        ts: DateTime<Utc>,
+        code: RejectionCode,
+        reason: String,
+    },
+
+    #[error("invalid_request")]
+    InvalidRequest { reason: String },
+
+    #[error("invalid_market")]
+    InvalidMarket { reason: String },
+
+    #[error("market_already_listed")]
+    MarketAlreadyListed { ticker: String },
+
+    #[error("market_not_found")]
+    MarketNotFound { ticker: String },
 
     #[error("database_error")]
     Database(#[from] sqlx::Error),
@@ -47,9 +119,26 @@ pub enum Error {
 pub(crate) type Result<T, E = Error> = std::result::Result<T, E>;
 
 impl Error {
-    pub fn event_rejection(ts: DateTime<Utc>, reason: impl Into<String>) -> Self {
+    pub fn event_rejection(
+        ts: DateTime<Utc>,
+        code: RejectionCode,
+        reason: impl Into<String>,
+    ) -> Self {
         Self::EventRejection {
             ts,
+            code,
+            reason: reason.into(),
+        }
+    }
+
+    pub fn invalid_request(reason: impl Into<String>) -> Self {
+        Self::InvalidRequest {
+            reason: reason.into(),
+        }
+    }
+
+    pub fn invalid_market(reason: impl Into<String>) -> Self {
+        Self::InvalidMarket {
             reason: reason.into(),
         }
     }
@@ -3,10 +3,12 @@ use axum::Router;
 use orderbook_api_rs::actor;
 use orderbook_api_rs::database;
 use orderbook_api_rs::endpoints;
+use orderbook_api_rs::registry::{self, Market};
 use orderbook_api_rs::AppContext;
 use orderbook_api_rs::Config;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tower_http::trace::TraceLayer;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
@@ -22,12 +24,28 @@ async fn main() -> anyhow::Result<()> {
     let db = database::connect(&config).await?;
     database::run_migrations(&db).await?;
 
-    let (client, actor) = actor::build(db.clone(), "vibranium", 8);
+    let snapshot_policy = actor::SnapshotPolicy {
+        every_events: config.snapshot_every_events,
+        every: config.snapshot_every_seconds.map(Duration::from_secs),
+    };
+    let (markets, launcher) = registry::build(
+        db.clone(),
+        8,
+        snapshot_policy,
+        config.session_end,
+        config.self_trade_prevention,
+        config.check_invariants,
+    );
+    let initial_markets: Vec<Market> = config
+        .markets
+        .iter()
+        .map(|ticker| Market::new(ticker, config.tick_size))
+        .collect();
 
     let app_state = AppContext {
         db,
         config: Arc::new(config),
-        actor_client: client,
+        markets: markets.clone(),
     };
 
     let app = Router::new()
@@ -40,10 +58,14 @@ async fn main() -> anyhow::Result<()> {
         .serve(app.into_make_service())
         .with_graceful_shutdown(shutdown_signal());
 
-    tracing::info!("Server running, listening on {}", address);
     tokio::select! {
-        _ = actor.run() => {},
-        _ = http_server => {},
+        _ = launcher.run() => {},
+        result = async {
+            markets.restore(initial_markets).await?;
+            tracing::info!("Server running, listening on {}", address);
+            http_server.await?;
+            anyhow::Ok(())
+        } => result?,
     }
 
     Ok(())
@@ -4,38 +4,105 @@ use std::{
     rc::Rc,
 };
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, NaiveTime, TimeZone, Utc};
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use self::{
+    clock::{Clock, SystemClock},
+    ids::{IdGenerator, RandomIds},
+};
+
+pub mod clock;
+pub mod ids;
+pub mod invariants;
+#[cfg(test)]
+mod reference;
+
 #[derive(Debug, Deserialize)]
 pub enum Command {
     Buy {
         quantity: u32,
         price: Decimal,
+        time_in_force: TimeInForce,
+        post_only: Option<PostOnly>,
+        owner: Option<String>,
     },
     Sell {
         quantity: u32,
         price: Decimal,
+        time_in_force: TimeInForce,
+        post_only: Option<PostOnly>,
+        owner: Option<String>,
+    },
+    MarketBuy {
+        quantity: u32,
+    },
+    MarketSell {
+        quantity: u32,
+    },
+    /// Stop orders wait off the book until the last trade price reaches the
+    /// trigger price, a buy stop triggers at or above it and a sell stop at or
+    /// below it, becoming a limit order when a limit price is given or a
+    /// market order otherwise.
+    BuyStop {
+        quantity: u32,
+        trigger_price: Decimal,
+        limit_price: Option<Decimal>,
+        time_in_force: TimeInForce,
+    },
+    SellStop {
+        quantity: u32,
+        trigger_price: Decimal,
+        limit_price: Option<Decimal>,
+        time_in_force: TimeInForce,
+    },
+    /// Iceberg orders only show `display_quantity` on the book, the rest of
+    /// the quantity is kept hidden and shown a slice at a time.
+    BuyIceberg {
+        quantity: u32,
+        display_quantity: u32,
+        price: Decimal,
+        time_in_force: TimeInForce,
+    },
+    SellIceberg {
+        quantity: u32,
+        display_quantity: u32,
+        price: Decimal,
+        time_in_force: TimeInForce,
     },
+    /// Without a side the order is canceled whatever its side is, with one
+    /// an order of the other side is rejected.
     Cancel {
         id: Uuid,
+        side: Option<OrderType>,
     },
+    /// Cancels every order, resting or waiting to be triggered.
+    CancelAll,
+    /// Like `Cancel`, the side is checked when given.
     Update {
         id: Uuid,
+        side: Option<OrderType>,
         new_quantity: u32,
         new_price: Decimal,
     },
     GetState,
+    /// The book aggregated by price level, best `levels` of each side.
+    GetDepth {
+        levels: usize,
+    },
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub enum Event {
+    /// The incoming `order` traded with the resting `counterpart`, both as
+    /// they were before the trade.
     Filled {
         ts: DateTime<Utc>,
         order: Order,
         counterpart: Order,
+        trade: Trade,
     },
     Accepted {
         ts: DateTime<Utc>,
@@ -45,13 +112,137 @@ pub enum Event {
         ts: DateTime<Utc>,
         order: Order,
     },
+    Expired {
+        ts: DateTime<Utc>,
+        order: Order,
+    },
+    Triggered {
+        ts: DateTime<Utc>,
+        order: Order,
+    },
+    Replenished {
+        ts: DateTime<Utc>,
+        order: Order,
+    },
+    Exhausted {
+        ts: DateTime<Utc>,
+        order: Order,
+    },
+    /// The order was changed in place, keeping its id, `order` is the amended
+    /// order, its `ts` only changes when it lost its time priority.
+    Amended {
+        ts: DateTime<Utc>,
+        order: Order,
+    },
+    /// The incoming order was canceled instead of trading with a resting
+    /// order of the same owner.
+    SelfTradeCanceledNewest {
+        ts: DateTime<Utc>,
+        order: Order,
+        counterpart: Order,
+    },
+    /// The resting order was canceled instead of trading with an incoming
+    /// order of the same owner.
+    SelfTradeCanceledOldest {
+        ts: DateTime<Utc>,
+        order: Order,
+        counterpart: Order,
+    },
+    SelfTradeCanceledBoth {
+        ts: DateTime<Utc>,
+        order: Order,
+        counterpart: Order,
+    },
+    /// Both orders were decreased by the quantity they would have traded,
+    /// canceling the smaller one.
+    SelfTradeDecremented {
+        ts: DateTime<Utc>,
+        order: Order,
+        counterpart: Order,
+    },
     Rejected {
         ts: DateTime<Utc>,
+        code: RejectionCode,
         reason: String,
     },
     State {
         state: OrderBookState,
     },
+    Depth {
+        depth: OrderBookDepth,
+    },
+}
+
+impl Event {
+    /// The event as published to everyone, with its orders as `Order::public`
+    /// shows them.
+    pub fn public(&self) -> Event {
+        let mut event = self.clone();
+        match &mut event {
+            Event::Filled {
+                order, counterpart, ..
+            }
+            | Event::SelfTradeCanceledNewest {
+                order, counterpart, ..
+            }
+            | Event::SelfTradeCanceledOldest {
+                order, counterpart, ..
+            }
+            | Event::SelfTradeCanceledBoth {
+                order, counterpart, ..
+            }
+            | Event::SelfTradeDecremented {
+                order, counterpart, ..
+            } => {
+                *order = order.public();
+                *counterpart = counterpart.public();
+            }
+            Event::Accepted { order, .. }
+            | Event::Canceled { order, .. }
+            | Event::Expired { order, .. }
+            | Event::Triggered { order, .. }
+            | Event::Replenished { order, .. }
+            | Event::Exhausted { order, .. }
+            | Event::Amended { order, .. } => *order = order.public(),
+            Event::State { state } => *state = state.public(),
+            Event::Rejected { .. } | Event::Depth { .. } => {}
+        }
+        event
+    }
+}
+
+/// An execution between a resting order, the maker, and an incoming order,
+/// the taker, always at the maker's price. Remaining quantities are the ones
+/// shown on the book after the trade, an iceberg taker shows at most its
+/// display quantity.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct Trade {
+    pub id: Uuid,
+    pub ts: DateTime<Utc>,
+    pub price: Decimal,
+    pub quantity: u32,
+    pub maker_order_id: Uuid,
+    pub taker_order_id: Uuid,
+    pub aggressor_side: OrderType,
+    pub maker_remaining_quantity: u32,
+    pub taker_remaining_quantity: u32,
+}
+
+impl Trade {
+    fn new(id: Uuid, ts: DateTime<Utc>, taker: &Order, maker: &Order) -> Self {
+        let quantity = taker.quantity.min(maker.quantity);
+        Trade {
+            id,
+            ts,
+            price: maker.price,
+            quantity,
+            maker_order_id: maker.id,
+            taker_order_id: taker.id,
+            aggressor_side: taker.order_type,
+            maker_remaining_quantity: maker.quantity - quantity,
+            taker_remaining_quantity: taker.shown(taker.quantity - quantity),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, PartialOrd, Eq, Clone, Copy, Serialize, Deserialize)]
@@ -60,19 +251,192 @@ pub enum OrderType {
     Buy,
 }
 
+/// A limit order rests on the book at its price, a market order takes
+/// liquidity at any price and never rests, its price is meaningless.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum OrderKind {
+    #[default]
+    Limit,
+    Market,
+}
+
+/// How long an order stays on the book: until canceled, not at all (any
+/// unfilled quantity is canceled), not at all and only if it can be fully
+/// filled right away, or until the end of the trading session.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum TimeInForce {
+    #[default]
+    GoodTillCancel,
+    ImmediateOrCancel,
+    FillOrKill,
+    Day,
+}
+
+/// Why a command was rejected, the human readable reason comes along.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectionCode {
+    OrderNotFound,
+    InvalidQuantity,
+    InvalidPrice,
+    InvalidDisplayQuantity,
+    SideMismatch,
+    TickSize,
+    LotSize,
+    MinQuantity,
+    MaxQuantity,
+    MinNotional,
+    MinPrice,
+    MaxPrice,
+    PriceBand,
+    PostOnlyWouldTakeLiquidity,
+    FillOrKillNotFilled,
+    StopOrderNotAmendable,
+}
+
+/// The rules the orders of a market follow besides the tick size. Quantities
+/// are multiples of the lot size, and the price of limit orders may deviate
+/// from the last trade price by at most `price_band`, a fraction of it. By
+/// default any positive quantity is accepted at any positive price.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct InstrumentRules {
+    pub lot_size: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_quantity: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_quantity: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_notional: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_price: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_price: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub price_band: Option<Decimal>,
+}
+
+impl Default for InstrumentRules {
+    fn default() -> Self {
+        Self {
+            lot_size: 1,
+            min_quantity: None,
+            max_quantity: None,
+            min_notional: None,
+            min_price: None,
+            max_price: None,
+            price_band: None,
+        }
+    }
+}
+
+/// What happens to a post-only order that would take liquidity when placed:
+/// it is rejected, or re-priced one tick away from the best opposite price.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum PostOnly {
+    Reject,
+    Reprice,
+}
+
+/// What happens when an incoming order would trade with a resting order of
+/// the same owner.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum SelfTradePrevention {
+    #[default]
+    CancelNewest,
+    CancelOldest,
+    CancelBoth,
+    DecrementAndCancel,
+}
+
+impl TimeInForce {
+    fn rests(&self) -> bool {
+        matches!(self, Self::GoodTillCancel | Self::Day)
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub struct Order {
     pub order_type: OrderType,
+    #[serde(default)]
+    pub kind: OrderKind,
+    #[serde(default)]
+    pub time_in_force: TimeInForce,
     pub id: Uuid,
     pub ts: DateTime<Utc>,
     pub quantity: u32,
     pub price: Decimal,
+    /// Set while a stop order is waiting to be triggered.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trigger_price: Option<Decimal>,
+    /// Set for iceberg orders, the size of each slice shown on the book.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_quantity: Option<u32>,
+    /// Hidden quantity of an iceberg order resting on the book, besides the
+    /// shown `quantity`. Never exposed.
+    #[serde(skip)]
+    pub reserve_quantity: u32,
+    /// Set for orders that must never take liquidity.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_only: Option<PostOnly>,
+    /// Account owning the order, orders of the same owner never trade with
+    /// each other.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
 }
 
-#[derive(Debug, PartialEq, PartialOrd, Eq, Clone, Serialize, Deserialize)]
+#[derive(Debug, Default, PartialEq, PartialOrd, Eq, Clone, Serialize, Deserialize)]
 pub struct OrderBookState {
     pub buy: Vec<Order>,
     pub sell: Vec<Order>,
+    #[serde(default)]
+    pub stop: Vec<Order>,
+    #[serde(default)]
+    pub last_price: Option<Decimal>,
+}
+
+/// The orders resting at a price, only the shown quantity of iceberg orders
+/// is counted.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct PriceLevel {
+    pub price: Decimal,
+    pub quantity: u64,
+    pub order_count: usize,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize)]
+pub struct OrderBookDepth {
+    pub buy: Vec<PriceLevel>,
+    pub sell: Vec<PriceLevel>,
+}
+
+impl OrderBookDepth {
+    fn new(order_book: &OrderBook, levels: usize) -> Self {
+        Self {
+            buy: OrderBookDepth::levels(&order_book.buy_book, levels),
+            sell: OrderBookDepth::levels(&order_book.sell_book, levels),
+        }
+    }
+
+    /// Aggregates the book, already sorted best price first, by price.
+    fn levels(book: &BTreeSet<Rc<Order>>, levels: usize) -> Vec<PriceLevel> {
+        let mut result: Vec<PriceLevel> = Vec::new();
+        for order in book {
+            if let Some(level) = result.last_mut().filter(|level| level.price == order.price) {
+                level.quantity += order.quantity as u64;
+                level.order_count += 1;
+                continue;
+            }
+            if result.len() == levels {
+                break;
+            }
+            result.push(PriceLevel {
+                price: order.price,
+                quantity: order.quantity as u64,
+                order_count: 1,
+            });
+        }
+        result
+    }
 }
 
 impl OrderBookState {
@@ -90,27 +454,111 @@ impl OrderBookState {
                 .into_iter()
                 .map(|rc| (*rc).clone())
                 .collect(),
+            stop: order_book.stop_orders(),
+            last_price: order_book.last_price,
+        }
+    }
+
+    /// The book as published to everyone, with its orders as `Order::public`
+    /// shows them.
+    pub fn public(&self) -> Self {
+        let public = |orders: &[Order]| orders.iter().map(Order::public).collect();
+        Self {
+            buy: public(&self.buy),
+            sell: public(&self.sell),
+            stop: public(&self.stop),
+            last_price: self.last_price,
         }
     }
 }
 
 impl Order {
-    pub fn sell(ts: DateTime<Utc>, quantity: u32, price: Decimal) -> Self {
+    pub fn sell(id: Uuid, ts: DateTime<Utc>, quantity: u32, price: Decimal) -> Self {
         Self {
-            id: Uuid::new_v4(),
+            id,
             order_type: OrderType::Sell,
+            kind: OrderKind::Limit,
+            time_in_force: TimeInForce::GoodTillCancel,
             ts,
             quantity,
             price,
+            trigger_price: None,
+            display_quantity: None,
+            reserve_quantity: 0,
+            post_only: None,
+            owner: None,
         }
     }
-    pub fn buy(ts: DateTime<Utc>, quantity: u32, price: Decimal) -> Self {
+    pub fn buy(id: Uuid, ts: DateTime<Utc>, quantity: u32, price: Decimal) -> Self {
         Self {
-            id: Uuid::new_v4(),
+            id,
             order_type: OrderType::Buy,
+            kind: OrderKind::Limit,
+            time_in_force: TimeInForce::GoodTillCancel,
             ts,
             quantity,
             price,
+            trigger_price: None,
+            display_quantity: None,
+            reserve_quantity: 0,
+            post_only: None,
+            owner: None,
+        }
+    }
+    pub fn market_sell(id: Uuid, ts: DateTime<Utc>, quantity: u32) -> Self {
+        Self {
+            kind: OrderKind::Market,
+            time_in_force: TimeInForce::ImmediateOrCancel,
+            ..Order::sell(id, ts, quantity, Decimal::ZERO)
+        }
+    }
+    pub fn market_buy(id: Uuid, ts: DateTime<Utc>, quantity: u32) -> Self {
+        Self {
+            kind: OrderKind::Market,
+            time_in_force: TimeInForce::ImmediateOrCancel,
+            ..Order::buy(id, ts, quantity, Decimal::ZERO)
+        }
+    }
+
+    fn self_trades(&self, other: &Order) -> bool {
+        self.owner.is_some() && self.owner == other.owner
+    }
+
+    fn is_iceberg(&self) -> bool {
+        self.display_quantity.is_some()
+    }
+
+    /// The order as others see it: only the shown quantity of an iceberg
+    /// order, without its owner.
+    pub fn public(&self) -> Order {
+        Order {
+            quantity: self.shown(self.quantity),
+            reserve_quantity: 0,
+            owner: None,
+            ..self.clone()
+        }
+    }
+
+    /// How much of a quantity left of the order it shows on the book.
+    fn shown(&self, quantity: u32) -> u32 {
+        self.display_quantity
+            .map_or(quantity, |display_quantity| quantity.min(display_quantity))
+    }
+
+    /// The order as it rests on the book, an iceberg order only shows up to
+    /// its display quantity and keeps the rest in reserve.
+    fn sliced(self) -> Self {
+        match self.display_quantity {
+            Some(display_quantity) => {
+                let total = self.quantity + self.reserve_quantity;
+                let quantity = total.min(display_quantity);
+                Order {
+                    quantity,
+                    reserve_quantity: total - quantity,
+                    ..self
+                }
+            }
+            None => self,
         }
     }
 }
@@ -119,11 +567,11 @@ impl Ord for Order {
     fn cmp(&self, other: &Self) -> Ordering {
         match self.order_type {
             OrderType::Sell => match self.price.cmp(&other.price) {
-                Ordering::Equal => self.ts.cmp(&other.ts),
+                Ordering::Equal => self.ts.cmp(&other.ts).then(self.id.cmp(&other.id)),
                 ord => ord,
             },
             OrderType::Buy => match self.price.cmp(&other.price).reverse() {
-                Ordering::Equal => self.ts.cmp(&other.ts),
+                Ordering::Equal => self.ts.cmp(&other.ts).then(self.id.cmp(&other.id)),
                 ord => ord,
             },
         }
@@ -132,132 +580,934 @@ impl Ord for Order {
 
 impl PartialOrd for Order {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        match self.order_type.partial_cmp(&other.order_type) {
-            Some(core::cmp::Ordering::Equal) => {}
-            ord => return ord,
-        }
-        match self.id.partial_cmp(&other.id) {
-            Some(core::cmp::Ordering::Equal) => {}
-            ord => return ord,
-        }
-        match self.ts.partial_cmp(&other.ts) {
-            Some(core::cmp::Ordering::Equal) => {}
-            ord => return ord,
-        }
-        match self.quantity.partial_cmp(&other.quantity) {
-            Some(core::cmp::Ordering::Equal) => {}
-            ord => return ord,
-        }
-        self.price.partial_cmp(&other.price)
+        Some(self.cmp(other))
     }
 }
 
 #[derive(Debug)]
 pub struct OrderBook {
     pub ticker: String,
-    ts: DateTime<Utc>,
+    /// When the last command was processed, or the last event applied.
+    ts: Option<DateTime<Utc>>,
+    clock: Box<dyn Clock>,
+    ids: Box<dyn IdGenerator>,
+    session_end: NaiveTime,
+    tick_size: Decimal,
+    rules: InstrumentRules,
+    self_trade_prevention: SelfTradePrevention,
     sell_book: BTreeSet<Rc<Order>>,
     sell_index: HashMap<Uuid, Rc<Order>>,
     buy_book: BTreeSet<Rc<Order>>,
     buy_index: HashMap<Uuid, Rc<Order>>,
+    stop_index: HashMap<Uuid, Order>,
+    last_price: Option<Decimal>,
+    check_invariants: bool,
 }
 
 impl OrderBook {
     pub fn new(ticker: &str) -> Self {
         OrderBook {
-            ts: Utc::now(),
+            ts: None,
+            clock: Box::new(SystemClock),
+            ids: Box::new(RandomIds),
+            session_end: NaiveTime::MIN,
+            tick_size: Decimal::new(1, 2),
+            rules: InstrumentRules::default(),
+            self_trade_prevention: SelfTradePrevention::default(),
             ticker: ticker.to_owned(),
             sell_book: BTreeSet::new(),
             sell_index: HashMap::new(),
             buy_book: BTreeSet::new(),
             buy_index: HashMap::new(),
+            stop_index: HashMap::new(),
+            last_price: None,
+            check_invariants: false,
+        }
+    }
+
+    /// Sets the time of the day, in UTC, when the trading session ends and
+    /// the day orders expire, midnight by default.
+    pub fn with_session_end(mut self, session_end: NaiveTime) -> Self {
+        self.session_end = session_end;
+        self
+    }
+
+    /// Sets the minimum price increment, prices are multiples of it and
+    /// post-only orders are re-priced by it, 0.01 by default.
+    pub fn with_tick_size(mut self, tick_size: Decimal) -> Self {
+        self.tick_size = tick_size;
+        self
+    }
+
+    /// Sets the rules incoming orders must follow, orders breaking them are
+    /// rejected.
+    pub fn with_rules(mut self, rules: InstrumentRules) -> Self {
+        self.rules = rules;
+        self
+    }
+
+    /// Sets how self-trades are prevented, canceling the incoming order by
+    /// default.
+    pub fn with_self_trade_prevention(
+        mut self,
+        self_trade_prevention: SelfTradePrevention,
+    ) -> Self {
+        self.self_trade_prevention = self_trade_prevention;
+        self
+    }
+
+    /// Verifies the [invariants] after every command, panicking when one is
+    /// violated. Only done by debug builds, off by default.
+    pub fn with_invariant_checks(mut self, check_invariants: bool) -> Self {
+        self.check_invariants = check_invariants;
+        self
+    }
+
+    /// Sets the clock telling when commands are processed, the system clock
+    /// by default.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Sets the generator of the ids of new orders and trades, random ids by
+    /// default.
+    pub fn with_ids(mut self, ids: impl IdGenerator + 'static) -> Self {
+        self.ids = Box::new(ids);
+        self
+    }
+
+    /// Rebuilds an order book from a previously taken state, then applies, in
+    /// order, the events emitted by [`OrderBook::process`] after it.
+    pub fn restore(
+        ticker: &str,
+        state: OrderBookState,
+        events: impl IntoIterator<Item = Event>,
+    ) -> Self {
+        let mut order_book = OrderBook::new(ticker);
+        for order in state.buy.into_iter().chain(state.sell).chain(state.stop) {
+            order_book.insert(order);
+        }
+        order_book.last_price = state.last_price;
+        for event in events {
+            order_book.apply(&event);
+        }
+        order_book
+    }
+
+    pub fn state(&self) -> OrderBookState {
+        OrderBookState::new(self)
+    }
+
+    pub fn best_bid(&self) -> Option<Decimal> {
+        self.buy_book.first().map(|order| order.price)
+    }
+
+    pub fn best_ask(&self) -> Option<Decimal> {
+        self.sell_book.first().map(|order| order.price)
+    }
+
+    pub fn depth(&self, levels: usize) -> OrderBookDepth {
+        OrderBookDepth::new(self, levels)
+    }
+
+    /// The orders resting at `price` on one side of the book, an empty level
+    /// when there are none.
+    pub fn price_level(&self, order_type: OrderType, price: Decimal) -> PriceLevel {
+        let book = match order_type {
+            OrderType::Sell => &self.sell_book,
+            OrderType::Buy => &self.buy_book,
+        };
+        let mut level = PriceLevel {
+            price,
+            quantity: 0,
+            order_count: 0,
+        };
+        for order in book
+            .iter()
+            .skip_while(|order| order.price != price)
+            .take_while(|order| order.price == price)
+        {
+            level.quantity += order.quantity as u64;
+            level.order_count += 1;
+        }
+        level
+    }
+
+    /// A resting order or a pending stop order.
+    pub fn order(&self, id: &Uuid) -> Option<&Order> {
+        self.sell_index
+            .get(id)
+            .or_else(|| self.buy_index.get(id))
+            .map(|order| order.as_ref())
+            .or_else(|| self.stop_index.get(id))
+    }
+
+    /// The pending stop orders, earliest first.
+    fn stop_orders(&self) -> Vec<Order> {
+        let mut orders: Vec<Order> = self.stop_index.values().cloned().collect();
+        orders.sort_by_key(|order| (order.ts, order.id));
+        orders
+    }
+
+    /// Applies the state change described by an already emitted event.
+    ///
+    /// An accepted order is placed on its side of the book, or with the stop
+    /// orders, replacing any previous version with the same id, a fill
+    /// decreases both orders by the traded quantity, a replenished iceberg
+    /// slice is placed back, a self-trade prevention cancels or decreases the
+    /// orders involved and a cancel, expiration or trigger removes the order.
+    /// Incoming orders are placed on the book as soon as they are accepted,
+    /// the fills emitted by the same command take them out again.
+    pub fn apply(&mut self, event: &Event) {
+        match event {
+            Event::Accepted { ts, order } | Event::Amended { ts, order } => {
+                self.remove(&order.id);
+                self.insert(order.clone().sliced());
+                self.ts = Some(*ts);
+            }
+            Event::Filled { ts, trade, .. } => {
+                self.decrease_incoming(&trade.taker_order_id, trade.quantity);
+                self.decrease(&trade.maker_order_id, trade.quantity);
+                self.last_price = Some(trade.price);
+                self.ts = Some(*ts);
+            }
+            Event::Replenished { ts, order } => {
+                self.remove(&order.id);
+                self.insert(order.clone());
+                self.ts = Some(*ts);
+            }
+            Event::SelfTradeCanceledNewest { ts, order, .. } => {
+                self.remove(&order.id);
+                self.ts = Some(*ts);
+            }
+            Event::SelfTradeCanceledOldest {
+                ts, counterpart, ..
+            } => {
+                self.remove(&counterpart.id);
+                self.ts = Some(*ts);
+            }
+            Event::SelfTradeCanceledBoth {
+                ts,
+                order,
+                counterpart,
+            } => {
+                self.remove(&order.id);
+                self.remove(&counterpart.id);
+                self.ts = Some(*ts);
+            }
+            Event::SelfTradeDecremented {
+                ts,
+                order,
+                counterpart,
+            } => {
+                let quantity = order.quantity.min(counterpart.quantity);
+                self.decrease_incoming(&order.id, quantity);
+                self.decrease(&counterpart.id, quantity);
+                self.ts = Some(*ts);
+            }
+            Event::Canceled { ts, order }
+            | Event::Expired { ts, order }
+            | Event::Triggered { ts, order }
+            | Event::Exhausted { ts, order } => {
+                self.remove(&order.id);
+                self.ts = Some(*ts);
+            }
+            Event::Rejected { .. } | Event::State { .. } | Event::Depth { .. } => (),
+        }
+    }
+
+    fn insert(&mut self, order: Order) {
+        if order.trigger_price.is_some() {
+            self.stop_index.insert(order.id, order);
+            return;
+        }
+        let (book, index) = match order.order_type {
+            OrderType::Sell => (&mut self.sell_book, &mut self.sell_index),
+            OrderType::Buy => (&mut self.buy_book, &mut self.buy_index),
+        };
+        let rc = Rc::new(order);
+        book.insert(rc.clone());
+        index.insert(rc.id, rc);
+    }
+
+    fn remove(&mut self, id: &Uuid) -> Option<Order> {
+        if let Some(order) = self.stop_index.remove(id) {
+            return Some(order);
+        }
+        let order = match (self.sell_index.remove(id), self.buy_index.remove(id)) {
+            (Some(order), None) => {
+                self.sell_book.remove(&order);
+                order
+            }
+            (None, Some(order)) => {
+                self.buy_book.remove(&order);
+                order
+            }
+            (None, None) => return None,
+            (Some(_), Some(_)) => {
+                panic!("Bug, order found in both sides");
+            }
+        };
+        Some(order.as_ref().clone())
+    }
+
+    fn decrease(&mut self, id: &Uuid, quantity: u32) {
+        if let Some(order) = self.remove(id) {
+            if order.quantity > quantity {
+                self.insert(Order {
+                    quantity: order.quantity - quantity,
+                    ..order
+                });
+            }
+        }
+    }
+
+    /// Decreases the order being matched, which matches with its whole
+    /// quantity, also the part an iceberg order keeps in reserve once rested.
+    fn decrease_incoming(&mut self, id: &Uuid, quantity: u32) {
+        if let Some(order) = self.remove(id) {
+            let total = order.quantity + order.reserve_quantity;
+            if total > quantity {
+                self.insert(
+                    Order {
+                        quantity: total - quantity,
+                        reserve_quantity: 0,
+                        ..order
+                    }
+                    .sliced(),
+                );
+            }
         }
     }
 
+    /// Expires the day orders once a session ended since the last processed
+    /// command, as processing the next command would.
+    pub fn expire_orders(&mut self) -> Vec<Event> {
+        let ts = self.clock.now();
+        let events = self.process_expired_orders(ts);
+        self.ts = Some(ts);
+        self.verified(events)
+    }
+
     pub fn process(&mut self, command: Command) -> Vec<Event> {
-        let ts = Utc::now();
+        let ts = self.clock.now();
+        let mut events = self.process_expired_orders(ts);
+        if let Err((code, reason)) = self.check(&command) {
+            events.push(Event::Rejected { ts, code, reason });
+            return self.verified(events);
+        }
         match command {
-            Command::Buy { quantity, price } => {
-                let mut events = vec![];
-                self.process_buy_order(ts, &mut events, Order::buy(ts, quantity, price));
-                self.ts = ts;
-                events
-            }
-            Command::Sell { quantity, price } => {
-                let mut events = vec![];
-                self.process_sell_order(ts, &mut events, Order::sell(ts, quantity, price));
-                self.ts = ts;
-                events
-            }
-            Command::Cancel { id } => {
-                let events = self.process_cancel_order(ts, id);
-                self.ts = ts;
-                events
+            Command::Buy {
+                quantity,
+                price,
+                time_in_force,
+                post_only,
+                owner,
+            } => {
+                let order = Order {
+                    time_in_force,
+                    post_only,
+                    owner,
+                    ..Order::buy(self.ids.next_id(), ts, quantity, price)
+                };
+                self.process_buy_order(ts, &mut events, order);
+            }
+            Command::Sell {
+                quantity,
+                price,
+                time_in_force,
+                post_only,
+                owner,
+            } => {
+                let order = Order {
+                    time_in_force,
+                    post_only,
+                    owner,
+                    ..Order::sell(self.ids.next_id(), ts, quantity, price)
+                };
+                self.process_sell_order(ts, &mut events, order);
+            }
+            Command::MarketBuy { quantity } => {
+                let order = Order::market_buy(self.ids.next_id(), ts, quantity);
+                self.process_buy_order(ts, &mut events, order);
+            }
+            Command::MarketSell { quantity } => {
+                let order = Order::market_sell(self.ids.next_id(), ts, quantity);
+                self.process_sell_order(ts, &mut events, order);
+            }
+            Command::BuyStop {
+                quantity,
+                trigger_price,
+                limit_price,
+                time_in_force,
+            } => {
+                let order = match limit_price {
+                    Some(price) => Order {
+                        time_in_force,
+                        ..Order::buy(self.ids.next_id(), ts, quantity, price)
+                    },
+                    None => Order::market_buy(self.ids.next_id(), ts, quantity),
+                };
+                self.process_stop_order(ts, &mut events, order, trigger_price);
+            }
+            Command::SellStop {
+                quantity,
+                trigger_price,
+                limit_price,
+                time_in_force,
+            } => {
+                let order = match limit_price {
+                    Some(price) => Order {
+                        time_in_force,
+                        ..Order::sell(self.ids.next_id(), ts, quantity, price)
+                    },
+                    None => Order::market_sell(self.ids.next_id(), ts, quantity),
+                };
+                self.process_stop_order(ts, &mut events, order, trigger_price);
+            }
+            Command::BuyIceberg {
+                quantity,
+                display_quantity,
+                price,
+                time_in_force,
+            } => {
+                let order = Order {
+                    time_in_force,
+                    display_quantity: Some(display_quantity),
+                    ..Order::buy(self.ids.next_id(), ts, quantity, price)
+                };
+                self.process_iceberg_order(ts, &mut events, order);
+            }
+            Command::SellIceberg {
+                quantity,
+                display_quantity,
+                price,
+                time_in_force,
+            } => {
+                let order = Order {
+                    time_in_force,
+                    display_quantity: Some(display_quantity),
+                    ..Order::sell(self.ids.next_id(), ts, quantity, price)
+                };
+                self.process_iceberg_order(ts, &mut events, order);
+            }
+            Command::Cancel { id, side } => {
+                events.extend(self.process_cancel_order(ts, id, side));
+            }
+            Command::CancelAll => {
+                events.extend(self.process_cancel_all_orders(ts));
             }
             Command::Update {
                 id,
+                side,
                 new_quantity,
                 new_price,
             } => {
-                let events = self.process_update_order(ts, id, new_quantity, new_price);
-                self.ts = ts;
-                events
+                events.extend(self.process_update_order(ts, id, side, new_quantity, new_price));
             }
             Command::GetState => {
-                vec![Event::State {
-                    state: OrderBookState::new(self),
-                }]
+                events.push(Event::State {
+                    state: self.state(),
+                });
+            }
+            Command::GetDepth { levels } => {
+                events.push(Event::Depth {
+                    depth: self.depth(levels),
+                });
             }
         }
+        self.process_triggered_orders(ts, &mut events);
+        self.ts = Some(ts);
+        self.verified(events)
     }
 
-    fn process_sell_order(&mut self, ts: DateTime<Utc>, events: &mut Vec<Event>, order: Order) {
-        OrderBook::process_order(
-            ts,
-            events,
-            order,
-            &mut self.buy_book,
-            &mut self.buy_index,
-            &mut self.sell_book,
-            &mut self.sell_index,
-        )
+    fn verified(&self, events: Vec<Event>) -> Vec<Event> {
+        if cfg!(debug_assertions) && self.check_invariants {
+            let verified = invariants::verify(self).and_then(|_| invariants::verify_fills(&events));
+            if let Err(violation) = verified {
+                panic!(
+                    "Order book {} invariant violated, {}",
+                    self.ticker, violation
+                );
+            }
+        }
+        events
     }
 
-    fn process_buy_order(&mut self, ts: DateTime<Utc>, events: &mut Vec<Event>, order: Order) {
-        OrderBook::process_order(
-            ts,
-            events,
-            order,
-            &mut self.sell_book,
-            &mut self.sell_index,
-            &mut self.buy_book,
+    /// Checks the orders placed or amended by a command against the tick size
+    /// and the instrument rules.
+    fn check(&self, command: &Command) -> Result<(), (RejectionCode, String)> {
+        match command {
+            Command::Buy {
+                quantity, price, ..
+            }
+            | Command::Sell {
+                quantity, price, ..
+            }
+            | Command::Update {
+                new_quantity: quantity,
+                new_price: price,
+                ..
+            } => self.check_order(*quantity, Some(*price), true),
+            Command::MarketBuy { quantity } | Command::MarketSell { quantity } => {
+                self.check_order(*quantity, None, false)
+            }
+            Command::BuyStop {
+                quantity,
+                trigger_price,
+                limit_price,
+                ..
+            }
+            | Command::SellStop {
+                quantity,
+                trigger_price,
+                limit_price,
+                ..
+            } => {
+                self.check_price(*trigger_price, false)?;
+                self.check_order(*quantity, *limit_price, false)
+            }
+            Command::BuyIceberg {
+                quantity,
+                display_quantity,
+                price,
+                ..
+            }
+            | Command::SellIceberg {
+                quantity,
+                display_quantity,
+                price,
+                ..
+            } => {
+                self.check_order(*quantity, Some(*price), true)?;
+                if !display_quantity.is_multiple_of(self.rules.lot_size) {
+                    return Err((
+                        RejectionCode::LotSize,
+                        format!(
+                            "Display quantity {} is not a multiple of the lot size {}",
+                            display_quantity, self.rules.lot_size
+                        ),
+                    ));
+                }
+                Ok(())
+            }
+            Command::Cancel { .. }
+            | Command::CancelAll
+            | Command::GetState
+            | Command::GetDepth { .. } => Ok(()),
+        }
+    }
+
+    /// Checks the quantity and, unless a market order, the price of an order,
+    /// `banded` when the price is checked against the last trade price.
+    fn check_order(
+        &self,
+        quantity: u32,
+        price: Option<Decimal>,
+        banded: bool,
+    ) -> Result<(), (RejectionCode, String)> {
+        let rules = &self.rules;
+        if quantity == 0 {
+            return Err((
+                RejectionCode::InvalidQuantity,
+                "Quantity must be positive".to_owned(),
+            ));
+        }
+        if !quantity.is_multiple_of(rules.lot_size) {
+            return Err((
+                RejectionCode::LotSize,
+                format!(
+                    "Quantity {} is not a multiple of the lot size {}",
+                    quantity, rules.lot_size
+                ),
+            ));
+        }
+        if let Some(min_quantity) = rules.min_quantity.filter(|min| quantity < *min) {
+            return Err((
+                RejectionCode::MinQuantity,
+                format!(
+                    "Quantity {} is below the minimum {}",
+                    quantity, min_quantity
+                ),
+            ));
+        }
+        if let Some(max_quantity) = rules.max_quantity.filter(|max| quantity > *max) {
+            return Err((
+                RejectionCode::MaxQuantity,
+                format!(
+                    "Quantity {} is above the maximum {}",
+                    quantity, max_quantity
+                ),
+            ));
+        }
+        let Some(price) = price else {
+            return Ok(());
+        };
+        self.check_price(price, banded)?;
+        let notional = price * Decimal::from(quantity);
+        if let Some(min_notional) = rules.min_notional.filter(|min| notional < *min) {
+            return Err((
+                RejectionCode::MinNotional,
+                format!(
+                    "Notional {} is below the minimum {}",
+                    notional, min_notional
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_price(&self, price: Decimal, banded: bool) -> Result<(), (RejectionCode, String)> {
+        let rules = &self.rules;
+        if price <= Decimal::ZERO {
+            return Err((
+                RejectionCode::InvalidPrice,
+                format!("Price {} must be positive", price),
+            ));
+        }
+        if !(price % self.tick_size).is_zero() {
+            return Err((
+                RejectionCode::TickSize,
+                format!(
+                    "Price {} is not a multiple of the tick size {}",
+                    price, self.tick_size
+                ),
+            ));
+        }
+        if let Some(min_price) = rules.min_price.filter(|min| price < *min) {
+            return Err((
+                RejectionCode::MinPrice,
+                format!("Price {} is below the minimum {}", price, min_price),
+            ));
+        }
+        if let Some(max_price) = rules.max_price.filter(|max| price > *max) {
+            return Err((
+                RejectionCode::MaxPrice,
+                format!("Price {} is above the maximum {}", price, max_price),
+            ));
+        }
+        if let (true, Some(band), Some(last_price)) = (banded, rules.price_band, self.last_price) {
+            if (price - last_price).abs() > last_price * band {
+                return Err((
+                    RejectionCode::PriceBand,
+                    format!(
+                        "Price {} deviates more than {} from the last trade price {}",
+                        price, band, last_price
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn process_iceberg_order(&mut self, ts: DateTime<Utc>, events: &mut Vec<Event>, order: Order) {
+        if order.display_quantity == Some(0) {
+            events.push(Event::Rejected {
+                ts,
+                code: RejectionCode::InvalidDisplayQuantity,
+                reason: "Iceberg order display quantity must be positive".to_owned(),
+            });
+            return;
+        }
+        match order.order_type {
+            OrderType::Buy => self.process_buy_order(ts, events, order),
+            OrderType::Sell => self.process_sell_order(ts, events, order),
+        }
+    }
+
+    fn process_stop_order(
+        &mut self,
+        ts: DateTime<Utc>,
+        events: &mut Vec<Event>,
+        order: Order,
+        trigger_price: Decimal,
+    ) {
+        let order = Order {
+            trigger_price: Some(trigger_price),
+            ..order
+        };
+        events.push(Event::Accepted {
+            ts,
+            order: order.clone(),
+        });
+        self.stop_index.insert(order.id, order);
+    }
+
+    fn triggers(order: &Order, last_price: Decimal) -> bool {
+        match (order.order_type, order.trigger_price) {
+            (OrderType::Buy, Some(trigger_price)) => last_price >= trigger_price,
+            (OrderType::Sell, Some(trigger_price)) => last_price <= trigger_price,
+            (_, None) => false,
+        }
+    }
+
+    /// Follows the last trade price through the fills emitted so far and
+    /// submits the stop orders it triggers, earliest first, until their own
+    /// fills trigger no other stop order.
+    fn process_triggered_orders(&mut self, ts: DateTime<Utc>, events: &mut Vec<Event>) {
+        let mut from = 0;
+        loop {
+            for event in &events[from..] {
+                if let Event::Filled { counterpart, .. } = event {
+                    self.last_price = Some(counterpart.price);
+                }
+            }
+            from = events.len();
+            let Some(last_price) = self.last_price else {
+                return;
+            };
+            let triggered: Vec<Order> = self
+                .stop_orders()
+                .into_iter()
+                .filter(|order| OrderBook::triggers(order, last_price))
+                .collect();
+            if triggered.is_empty() {
+                return;
+            }
+            for order in triggered {
+                self.stop_index.remove(&order.id);
+                events.push(Event::Triggered {
+                    ts,
+                    order: order.clone(),
+                });
+                let order = Order {
+                    ts,
+                    trigger_price: None,
+                    ..order
+                };
+                match order.order_type {
+                    OrderType::Buy => self.process_buy_order(ts, events, order),
+                    OrderType::Sell => self.process_sell_order(ts, events, order),
+                }
+            }
+        }
+    }
+
+    fn session_end_after(&self, ts: DateTime<Utc>) -> DateTime<Utc> {
+        let session_end = Utc.from_utc_datetime(&ts.date_naive().and_time(self.session_end));
+        if session_end > ts {
+            session_end
+        } else {
+            session_end + Duration::days(1)
+        }
+    }
+
+    /// Expires the day orders once a session ended since the last processed
+    /// command, the books are only scanned after a session end or on the
+    /// first command.
+    fn process_expired_orders(&mut self, ts: DateTime<Utc>) -> Vec<Event> {
+        if self
+            .ts
+            .is_some_and(|last| self.session_end_after(last) > ts)
+        {
+            return vec![];
+        }
+        let expired: Vec<Uuid> = self
+            .buy_book
+            .iter()
+            .chain(self.sell_book.iter())
+            .map(|rc| rc.as_ref().clone())
+            .chain(self.stop_orders())
+            .filter(|order| {
+                order.time_in_force == TimeInForce::Day && self.session_end_after(order.ts) <= ts
+            })
+            .map(|order| order.id)
+            .collect();
+        expired
+            .iter()
+            .filter_map(|id| self.remove(id))
+            .map(|order| Event::Expired { ts, order })
+            .collect()
+    }
+
+    fn process_sell_order(&mut self, ts: DateTime<Utc>, events: &mut Vec<Event>, order: Order) {
+        let Some(order) = self.post_only_order(ts, events, order) else {
+            return;
+        };
+        OrderBook::process_order(
+            ts,
+            events,
+            self.ids.as_mut(),
+            order,
+            self.self_trade_prevention,
+            &mut self.buy_book,
+            &mut self.buy_index,
+            &mut self.sell_book,
+            &mut self.sell_index,
+        )
+    }
+
+    fn process_buy_order(&mut self, ts: DateTime<Utc>, events: &mut Vec<Event>, order: Order) {
+        let Some(order) = self.post_only_order(ts, events, order) else {
+            return;
+        };
+        OrderBook::process_order(
+            ts,
+            events,
+            self.ids.as_mut(),
+            order,
+            self.self_trade_prevention,
+            &mut self.sell_book,
+            &mut self.sell_index,
+            &mut self.buy_book,
             &mut self.buy_index,
         )
     }
 
+    /// Keeps a post-only order from taking liquidity, rejecting it or moving
+    /// its price one tick away from the best opposite price when it would
+    /// cross the book.
+    fn post_only_order(
+        &self,
+        ts: DateTime<Utc>,
+        events: &mut Vec<Event>,
+        order: Order,
+    ) -> Option<Order> {
+        let Some(post_only) = order.post_only else {
+            return Some(order);
+        };
+        let best = match order.order_type {
+            OrderType::Buy => self.sell_book.first(),
+            OrderType::Sell => self.buy_book.first(),
+        };
+        let Some(best) = best.filter(|best| OrderBook::crosses(&order, best)) else {
+            return Some(order);
+        };
+        let price = match order.order_type {
+            OrderType::Buy => best.price - self.tick_size,
+            OrderType::Sell => best.price + self.tick_size,
+        };
+        if post_only == PostOnly::Reject || price <= Decimal::ZERO {
+            events.push(Event::Rejected {
+                ts,
+                code: RejectionCode::PostOnlyWouldTakeLiquidity,
+                reason: format!("Post-only order would take liquidity at {}", best.price),
+            });
+            return None;
+        }
+        Some(Order { price, ..order })
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn process_order(
         ts: DateTime<Utc>,
         events: &mut Vec<Event>,
+        ids: &mut dyn IdGenerator,
         order: Order,
+        self_trade_prevention: SelfTradePrevention,
         counterpart_book: &mut BTreeSet<Rc<Order>>,
         counterpart_index: &mut HashMap<Uuid, Rc<Order>>,
         source_book: &mut BTreeSet<Rc<Order>>,
         source_index: &mut HashMap<Uuid, Rc<Order>>,
     ) {
+        if order.time_in_force == TimeInForce::FillOrKill {
+            let available: u64 = counterpart_book
+                .iter()
+                .take_while(|counterpart| OrderBook::crosses(&order, counterpart))
+                // Only canceling the resting order lets the order trade past
+                // one of its owner, the other policies cancel it there.
+                .take_while(|counterpart| {
+                    self_trade_prevention == SelfTradePrevention::CancelOldest
+                        || !order.self_trades(counterpart)
+                })
+                .filter(|counterpart| !order.self_trades(counterpart))
+                .map(|counterpart| (counterpart.quantity + counterpart.reserve_quantity) as u64)
+                .sum();
+            if available < order.quantity as u64 {
+                events.push(Event::Rejected {
+                    ts,
+                    code: RejectionCode::FillOrKillNotFilled,
+                    reason: format!(
+                        "Fill or kill order of {} could not be fully filled, available {}",
+                        order.quantity, available
+                    ),
+                });
+                return;
+            }
+        }
         events.push(Event::Accepted {
             ts,
             order: order.clone(),
         });
-        match counterpart_book.pop_first() {
-            Some(counterpart) if order.price <= counterpart.price => {
+        match counterpart_book.first().cloned() {
+            Some(counterpart)
+                if OrderBook::crosses(&order, &counterpart) && order.self_trades(&counterpart) =>
+            {
+                let remaining = match self_trade_prevention {
+                    SelfTradePrevention::CancelNewest => {
+                        events.push(Event::SelfTradeCanceledNewest {
+                            ts,
+                            order: order.clone(),
+                            counterpart: counterpart.as_ref().clone(),
+                        });
+                        None
+                    }
+                    SelfTradePrevention::CancelOldest => {
+                        counterpart_book.remove(&counterpart);
+                        counterpart_index.remove(&counterpart.id);
+                        events.push(Event::SelfTradeCanceledOldest {
+                            ts,
+                            order: order.clone(),
+                            counterpart: counterpart.as_ref().clone(),
+                        });
+                        Some(order.clone())
+                    }
+                    SelfTradePrevention::CancelBoth => {
+                        counterpart_book.remove(&counterpart);
+                        counterpart_index.remove(&counterpart.id);
+                        events.push(Event::SelfTradeCanceledBoth {
+                            ts,
+                            order: order.clone(),
+                            counterpart: counterpart.as_ref().clone(),
+                        });
+                        None
+                    }
+                    SelfTradePrevention::DecrementAndCancel => {
+                        let quantity = order.quantity.min(counterpart.quantity);
+                        counterpart_book.remove(&counterpart);
+                        counterpart_index.remove(&counterpart.id);
+                        if counterpart.quantity > quantity {
+                            let rc = Rc::new(Order {
+                                quantity: counterpart.quantity - quantity,
+                                ..counterpart.as_ref().clone()
+                            });
+                            counterpart_book.insert(rc.clone());
+                            counterpart_index.insert(rc.id, rc);
+                        }
+                        events.push(Event::SelfTradeDecremented {
+                            ts,
+                            order: order.clone(),
+                            counterpart: counterpart.as_ref().clone(),
+                        });
+                        (order.quantity > quantity).then(|| Order {
+                            quantity: order.quantity - quantity,
+                            ..order.clone()
+                        })
+                    }
+                };
+                if let Some(order) = remaining {
+                    OrderBook::process_order(
+                        ts,
+                        events,
+                        ids,
+                        order,
+                        self_trade_prevention,
+                        counterpart_book,
+                        counterpart_index,
+                        source_book,
+                        source_index,
+                    )
+                }
+            }
+            Some(counterpart) if OrderBook::crosses(&order, &counterpart) => {
+                counterpart_book.remove(&counterpart);
+                counterpart_index.remove(&counterpart.id);
                 match order.quantity.cmp(&counterpart.quantity) {
                     Ordering::Less => {
                         let new_counterpart = Order {
-                            order_type: counterpart.order_type,
-                            id: counterpart.id,
-                            ts: counterpart.ts,
-                            price: counterpart.price,
                             quantity: counterpart.quantity - order.quantity,
+                            ..counterpart.as_ref().clone()
                         };
                         let rc = Rc::new(new_counterpart);
                         counterpart_book.insert(rc.clone());
@@ -266,49 +1516,146 @@ impl OrderBook {
                             ts,
                             order: order.clone(),
                             counterpart: counterpart.as_ref().clone(),
+                            trade: Trade::new(ids.next_id(), ts, &order, &counterpart),
                         });
+                        OrderBook::exhaust(ts, events, &order);
                     }
                     Ordering::Greater => {
-                        counterpart_book.remove(&order);
-                        counterpart_index.remove(&order.id);
                         events.push(Event::Filled {
                             ts,
                             order: order.clone(),
                             counterpart: counterpart.as_ref().clone(),
+                            trade: Trade::new(ids.next_id(), ts, &order, &counterpart),
                         });
+                        OrderBook::replenish(
+                            ts,
+                            events,
+                            &counterpart,
+                            counterpart_book,
+                            counterpart_index,
+                        );
                         let new_source_order = Order {
-                            order_type: order.order_type,
-                            id: order.id,
-                            ts: order.ts,
-                            price: order.price,
                             quantity: order.quantity - counterpart.quantity,
+                            ..order.clone()
                         };
                         OrderBook::process_order(
                             ts,
                             events,
+                            ids,
                             new_source_order,
+                            self_trade_prevention,
                             counterpart_book,
                             counterpart_index,
                             source_book,
                             source_index,
                         )
                     }
-                    Ordering::Equal => events.push(Event::Filled {
-                        ts,
-                        order: order.clone(),
-                        counterpart: counterpart.as_ref().clone(),
-                    }),
+                    Ordering::Equal => {
+                        events.push(Event::Filled {
+                            ts,
+                            order: order.clone(),
+                            counterpart: counterpart.as_ref().clone(),
+                            trade: Trade::new(ids.next_id(), ts, &order, &counterpart),
+                        });
+                        OrderBook::replenish(
+                            ts,
+                            events,
+                            &counterpart,
+                            counterpart_book,
+                            counterpart_index,
+                        );
+                        OrderBook::exhaust(ts, events, &order);
+                    }
                 }
             }
+            _ if !order.time_in_force.rests() => {
+                events.push(Event::Canceled { ts, order });
+            }
             _ => {
-                let rc = Rc::new(order);
+                let rc = Rc::new(order.sliced());
                 source_book.insert(rc.clone());
                 source_index.insert(rc.id, rc);
             }
         }
     }
 
-    fn process_cancel_order(&mut self, ts: DateTime<Utc>, id: Uuid) -> Vec<Event> {
+    /// Shows a new slice of a resting iceberg order whose shown quantity was
+    /// fully filled, taking it from the reserve and with a fresh time
+    /// priority, or reports the iceberg order exhausted.
+    fn replenish(
+        ts: DateTime<Utc>,
+        events: &mut Vec<Event>,
+        order: &Order,
+        book: &mut BTreeSet<Rc<Order>>,
+        index: &mut HashMap<Uuid, Rc<Order>>,
+    ) {
+        if order.reserve_quantity == 0 {
+            OrderBook::exhaust(ts, events, order);
+            return;
+        }
+        let slice = Order {
+            ts,
+            quantity: 0,
+            ..order.clone()
+        }
+        .sliced();
+        let rc = Rc::new(slice.clone());
+        book.insert(rc.clone());
+        index.insert(rc.id, rc);
+        events.push(Event::Replenished { ts, order: slice });
+    }
+
+    /// Reports a fully filled iceberg order.
+    fn exhaust(ts: DateTime<Utc>, events: &mut Vec<Event>, order: &Order) {
+        if order.is_iceberg() {
+            events.push(Event::Exhausted {
+                ts,
+                order: Order {
+                    quantity: 0,
+                    reserve_quantity: 0,
+                    ..order.clone()
+                },
+            });
+        }
+    }
+
+    /// Whether an incoming order trades with a resting one of the other
+    /// side: a buy at or above the ask, a sell at or below the bid, a market
+    /// order at any price.
+    fn crosses(order: &Order, counterpart: &Order) -> bool {
+        order.kind == OrderKind::Market
+            || match order.order_type {
+                OrderType::Buy => order.price >= counterpart.price,
+                OrderType::Sell => order.price <= counterpart.price,
+            }
+    }
+
+    /// Rejects acting on an order of the other side than the expected one.
+    fn check_side(&self, ts: DateTime<Utc>, id: Uuid, side: Option<OrderType>) -> Option<Event> {
+        let side = side?;
+        let order = self.order(&id).filter(|order| order.order_type != side)?;
+        Some(Event::Rejected {
+            ts,
+            code: RejectionCode::SideMismatch,
+            reason: format!(
+                "Order {} is a {:?} order, not a {:?} one",
+                id, order.order_type, side
+            ),
+        })
+    }
+
+    fn process_cancel_order(
+        &mut self,
+        ts: DateTime<Utc>,
+        id: Uuid,
+        side: Option<OrderType>,
+    ) -> Vec<Event> {
+        if let Some(rejected) = self.check_side(ts, id, side) {
+            return vec![rejected];
+        }
+        if let Some(order) = self.stop_index.remove(&id) {
+            return vec![Event::Canceled { ts, order }];
+        }
         match (self.sell_index.get(&id), self.buy_index.get(&id)) {
             (Some(sell_order), None) => {
                 let order: Order = sell_order.as_ref().clone();
@@ -324,6 +1671,7 @@ impl OrderBook {
             }
             (None, None) => vec![Event::Rejected {
                 ts,
+                code: RejectionCode::OrderNotFound,
                 reason: format!("Order {} not found in sell or buy side", id),
             }],
             (Some(_), Some(_)) => {
@@ -332,32 +1680,124 @@ impl OrderBook {
         }
     }
 
+    /// Cancels the sell orders then the buy orders, in book order, then the
+    /// stop orders, earliest first.
+    fn process_cancel_all_orders(&mut self, ts: DateTime<Utc>) -> Vec<Event> {
+        let ids: Vec<Uuid> = self
+            .sell_book
+            .iter()
+            .chain(self.buy_book.iter())
+            .map(|order| order.id)
+            .chain(self.stop_orders().into_iter().map(|order| order.id))
+            .collect();
+        ids.into_iter()
+            .flat_map(|id| self.process_cancel_order(ts, id, None))
+            .collect()
+    }
+
+    /// Amends an order in place, keeping its id. Decreasing or keeping the
+    /// quantity keeps the time priority, changing the price or increasing the
+    /// quantity loses it and the amended order may then match the opposite
+    /// book. Stop orders are not amended before they are triggered.
     fn process_update_order(
         &mut self,
         ts: DateTime<Utc>,
         id: Uuid,
+        side: Option<OrderType>,
         new_quantity: u32,
         new_price: Decimal,
     ) -> Vec<Event> {
-        match (self.sell_index.get(&id), self.buy_index.get(&id)) {
-            (Some(_), None) => {
-                let mut events = self.process_cancel_order(ts, id);
-                self.process_sell_order(ts, &mut events, Order::sell(ts, new_quantity, new_price));
-                events
-            }
-            (None, Some(_)) => {
-                let mut events = self.process_cancel_order(ts, id);
-                self.process_buy_order(ts, &mut events, Order::buy(ts, new_quantity, new_price));
-                events
+        if let Some(rejected) = self.check_side(ts, id, side) {
+            return vec![rejected];
+        }
+        if self.stop_index.contains_key(&id) {
+            return vec![Event::Rejected {
+                ts,
+                code: RejectionCode::StopOrderNotAmendable,
+                reason: format!(
+                    "Stop order {} can not be amended before it is triggered, cancel it instead",
+                    id
+                ),
+            }];
+        }
+        let order = match (self.sell_index.get(&id), self.buy_index.get(&id)) {
+            (Some(order), None) | (None, Some(order)) => order.as_ref().clone(),
+            (None, None) => {
+                return vec![Event::Rejected {
+                    ts,
+                    code: RejectionCode::OrderNotFound,
+                    reason: format!("Order {} not found in sell or buy side", id),
+                }]
             }
-            (None, None) => vec![Event::Rejected {
-                ts: Utc::now(),
-                reason: format!("Order {} not found in sell or buy side", id),
-            }],
             (Some(_), Some(_)) => {
                 panic!("Bug, order found in both sides");
             }
+        };
+        if new_quantity == 0 {
+            return vec![Event::Rejected {
+                ts,
+                code: RejectionCode::InvalidQuantity,
+                reason: format!("Order {} can not be amended to a zero quantity", id),
+            }];
+        }
+        let mut events = vec![];
+        if new_price == order.price && new_quantity <= order.quantity + order.reserve_quantity {
+            let order = Order {
+                quantity: new_quantity,
+                reserve_quantity: 0,
+                ..order
+            }
+            .sliced();
+            self.remove(&id);
+            self.insert(order.clone());
+            events.push(Event::Amended { ts, order });
+            return events;
+        }
+        let order = Order {
+            ts,
+            quantity: new_quantity,
+            price: new_price,
+            reserve_quantity: 0,
+            ..order
+        };
+        let Some(order) = self.post_only_order(ts, &mut events, order) else {
+            return events;
+        };
+        self.remove(&id);
+        let start = events.len();
+        let (counterpart_book, counterpart_index, source_book, source_index) =
+            match order.order_type {
+                OrderType::Sell => (
+                    &mut self.buy_book,
+                    &mut self.buy_index,
+                    &mut self.sell_book,
+                    &mut self.sell_index,
+                ),
+                OrderType::Buy => (
+                    &mut self.sell_book,
+                    &mut self.sell_index,
+                    &mut self.buy_book,
+                    &mut self.buy_index,
+                ),
+            };
+        OrderBook::process_order(
+            ts,
+            &mut events,
+            self.ids.as_mut(),
+            order,
+            self.self_trade_prevention,
+            counterpart_book,
+            counterpart_index,
+            source_book,
+            source_index,
+        );
+        if let Some(Event::Accepted { ts, order }) = events.get(start) {
+            events[start] = Event::Amended {
+                ts: *ts,
+                order: order.clone(),
+            };
         }
+        events
     }
 }
 
@@ -368,6 +1808,7 @@ mod tests {
     use rust_decimal_macros::dec;
 
     use super::*;
+    use crate::order_book::{clock::ManualClock, ids::SequentialIds};
 
     // fn print_order_book(order_book: &OrderBook) {
     //     println!();
@@ -384,13 +1825,13 @@ mod tests {
     fn test_order_on_same_price_should_be_ordered_by_earliest() {
         let ts = Utc::now();
         {
-            let order1 = Order::buy(ts, 10, dec!(1));
-            let order2 = Order::buy(ts + Duration::milliseconds(1), 10, dec!(1));
+            let order1 = Order::buy(Uuid::new_v4(), ts, 10, dec!(1));
+            let order2 = Order::buy(Uuid::new_v4(), ts + Duration::milliseconds(1), 10, dec!(1));
             assert_eq!(order1.cmp(&order2), Ordering::Less);
         }
         {
-            let order1 = Order::sell(ts, 10, dec!(1));
-            let order2 = Order::sell(ts + Duration::milliseconds(1), 10, dec!(1));
+            let order1 = Order::sell(Uuid::new_v4(), ts, 10, dec!(1));
+            let order2 = Order::sell(Uuid::new_v4(), ts + Duration::milliseconds(1), 10, dec!(1));
             assert_eq!(order1.cmp(&order2), Ordering::Less);
         }
     }
@@ -401,6 +1842,9 @@ mod tests {
         let events = order_book.process(Command::Buy {
             quantity: 5,
             price: dec!(2),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
         });
         let [
             Event::Accepted {
@@ -409,7 +1853,7 @@ mod tests {
                     order_type: OrderType::Buy,
                     id: _,
                     ts: _,
-                    quantity: 5, price
+                    quantity: 5, price, ..
                 }
             }] = &events[..] else {
             panic!("Wrong event type, events={:?}", events);
@@ -425,6 +1869,9 @@ mod tests {
         let events = order_book.process(Command::Sell {
             quantity: 5,
             price: dec!(2),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
         });
         let [
             Event::Accepted {
@@ -433,7 +1880,7 @@ mod tests {
                     order_type: OrderType::Sell,
                     id: _,
                     ts: _,
-                    quantity: 5, price
+                    quantity: 5, price, ..
                 }
             }] = &events[..] else {
             panic!("Wrong event type, events={:?}", events);
@@ -446,9 +1893,18 @@ mod tests {
     #[test]
     fn test_reject_cancel_of_non_existing_order() {
         let mut order_book = OrderBook::new("test");
-        let events = order_book.process(Command::Cancel { id: Uuid::new_v4() });
+        let events = order_book.process(Command::Cancel {
+            id: Uuid::new_v4(),
+            side: None,
+        });
         assert_eq!(events.len(), 1);
-        assert!(matches!(events.first().unwrap(), Event::Rejected { .. }));
+        assert!(matches!(
+            events.first().unwrap(),
+            Event::Rejected {
+                code: RejectionCode::OrderNotFound,
+                ..
+            }
+        ));
     }
 
     #[test]
@@ -457,6 +1913,9 @@ mod tests {
         let events = order_book.process(Command::Buy {
             quantity: 5,
             price: dec!(2),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
         });
         let [
             Event::Accepted {
@@ -466,65 +1925,423 @@ mod tests {
                     id,
                     ts:_,
                     quantity:_,
-                    price:_
+                    price:_,
+                    ..
                 }
             }] = &events[..] else {
             panic!("Wrong event type, events={:?}", events);
         };
-        let events = order_book.process(Command::Cancel { id: id.clone() });
+        let events = order_book.process(Command::Cancel {
+            id: *id,
+            side: None,
+        });
         assert_eq!(events.len(), 1);
         assert!(matches!(events.first().unwrap(), Event::Canceled { .. }));
         assert!(order_book.buy_book.is_empty());
     }
 
+    #[test]
+    fn test_cancel_and_amend_of_the_other_side_are_rejected() {
+        let mut order_book = OrderBook::new("test");
+        order_book.process(Command::Sell {
+            quantity: 5,
+            price: dec!(2),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
+        });
+        let id = order_book.state().sell[0].id;
+        let events = order_book.process(Command::Cancel {
+            id,
+            side: Some(OrderType::Buy),
+        });
+        let [Event::Rejected { code, .. }] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        assert_eq!(*code, RejectionCode::SideMismatch);
+        let events = order_book.process(Command::Update {
+            id,
+            side: Some(OrderType::Buy),
+            new_quantity: 3,
+            new_price: dec!(2),
+        });
+        let [Event::Rejected { code, .. }] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        assert_eq!(*code, RejectionCode::SideMismatch);
+        assert_eq!(order_book.state().sell[0].quantity, 5);
+
+        let events = order_book.process(Command::Update {
+            id,
+            side: Some(OrderType::Sell),
+            new_quantity: 3,
+            new_price: dec!(2),
+        });
+        assert!(matches!(&events[..], [Event::Amended { .. }]));
+        let events = order_book.process(Command::Cancel {
+            id,
+            side: Some(OrderType::Sell),
+        });
+        assert!(matches!(&events[..], [Event::Canceled { .. }]));
+        assert!(order_book.sell_book.is_empty());
+    }
+
     #[test]
     fn test_update_order() {
         let mut order_book = OrderBook::new("test");
         let events = order_book.process(Command::Buy {
             quantity: 5,
             price: dec!(2),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
         });
         let [Event::Accepted { ts:_, order: first_order }] = &events[..] else {
             panic!("Wrong event type, events={:?}", events);
         };
         let events = order_book.process(Command::Update {
             id: first_order.id,
+            side: None,
             new_quantity: 10,
             new_price: dec!(5.5),
         });
-        let [Event::Canceled { ts: _, order: first_order }, Event::Accepted { ts:_, order: updated_order }] = &events[..] else {
+        let [Event::Amended { ts, order: updated_order }] = &events[..] else {
             panic!("Wrong events={:?}", events);
         };
-        assert_ne!(first_order.id, updated_order.id);
+        assert_eq!(first_order.id, updated_order.id);
+        assert_eq!(updated_order.ts, *ts);
         assert_eq!(updated_order.quantity, 10);
         assert_eq!(updated_order.price, dec!(5.5));
         assert_eq!(order_book.buy_book.len(), 1);
     }
 
     #[test]
-    fn test_fill_buy_order_leaving_leftovers() {
+    fn test_amend_quantity_down_keeps_time_priority() {
+        let mut order_book = OrderBook::new("test");
+        let mut events = vec![];
+        for _ in 0..2 {
+            events.extend(order_book.process(Command::Sell {
+                quantity: 5,
+                price: dec!(2),
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                owner: None,
+            }));
+        }
+        let first = order_book.state().sell[0].clone();
+        let amend_events = order_book.process(Command::Update {
+            id: first.id,
+            side: None,
+            new_quantity: 3,
+            new_price: dec!(2),
+        });
+        let [Event::Amended { ts: _, order }] = &amend_events[..] else {
+            panic!("Wrong events={:?}", amend_events);
+        };
+        assert_eq!(order.id, first.id);
+        assert_eq!(order.ts, first.ts);
+        assert_eq!(order.quantity, 3);
+        let state = order_book.state();
+        assert_eq!(state.sell[0].id, first.id);
+        assert_eq!(state.sell[0].quantity, 3);
+
+        events.extend(amend_events);
+        let restored = OrderBook::restore("test", OrderBookState::default(), events);
+        assert_eq!(restored.state(), state);
+    }
+
+    #[test]
+    fn test_amend_to_the_same_quantity_and_price_keeps_time_priority() {
+        let mut order_book = OrderBook::new("test");
+        for _ in 0..2 {
+            order_book.process(Command::SellIceberg {
+                quantity: 6,
+                display_quantity: 2,
+                price: dec!(2),
+                time_in_force: TimeInForce::GoodTillCancel,
+            });
+        }
+        let first = order_book.state().sell[0].clone();
+        let amend_events = order_book.process(Command::Update {
+            id: first.id,
+            side: None,
+            new_quantity: 6,
+            new_price: dec!(2),
+        });
+        let [Event::Amended { ts: _, order }] = &amend_events[..] else {
+            panic!("Wrong events={:?}", amend_events);
+        };
+        assert_eq!(order.ts, first.ts);
+        let state = order_book.state();
+        assert_eq!(state.sell[0], first);
+    }
+
+    #[test]
+    fn test_stop_orders_are_not_amended() {
+        let mut order_book = OrderBook::new("test");
+        order_book.process(Command::SellStop {
+            quantity: 5,
+            trigger_price: dec!(2),
+            limit_price: None,
+            time_in_force: TimeInForce::GoodTillCancel,
+        });
+        let stop = order_book.state().stop[0].clone();
+        let events = order_book.process(Command::Update {
+            id: stop.id,
+            side: None,
+            new_quantity: 3,
+            new_price: dec!(2),
+        });
+        assert!(matches!(
+            events[..],
+            [Event::Rejected {
+                code: RejectionCode::StopOrderNotAmendable,
+                ..
+            }]
+        ));
+        assert_eq!(order_book.state().stop, vec![stop]);
+    }
+
+    #[test]
+    fn test_amend_price_loses_time_priority_and_may_match() {
+        let mut order_book = OrderBook::new("test");
+        let mut events = vec![];
+        for price in [dec!(3), dec!(3), dec!(1)] {
+            events.extend(order_book.process(Command::Buy {
+                quantity: 5,
+                price,
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                owner: None,
+            }));
+        }
+        let first = order_book.state().buy[0].clone();
+        let amend_events = order_book.process(Command::Update {
+            id: first.id,
+            side: None,
+            new_quantity: 6,
+            new_price: dec!(3),
+        });
+        let [Event::Amended { ts, order }] = &amend_events[..] else {
+            panic!("Wrong events={:?}", amend_events);
+        };
+        assert_eq!(order.id, first.id);
+        assert_eq!(order.ts, *ts);
+        let ids: Vec<Uuid> = order_book
+            .state()
+            .buy
+            .iter()
+            .map(|order| order.id)
+            .collect();
+        assert_eq!(ids[1], first.id);
+        events.extend(amend_events);
+
+        events.extend(order_book.process(Command::Sell {
+            quantity: 2,
+            price: dec!(4),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
+        }));
+        let sell = order_book.state().sell[0].clone();
+        let amend_events = order_book.process(Command::Update {
+            id: sell.id,
+            side: None,
+            new_quantity: 2,
+            new_price: dec!(3),
+        });
+        let [Event::Amended { ts: _, order }, Event::Filled { ts: _, order: filled, counterpart, .. }] =
+            &amend_events[..]
+        else {
+            panic!("Wrong events={:?}", amend_events);
+        };
+        assert_eq!(order.id, sell.id);
+        assert_eq!(filled.id, sell.id);
+        assert_eq!(counterpart.price, dec!(3));
+        assert!(order_book.state().sell.is_empty());
+        events.extend(amend_events);
+
+        let restored = OrderBook::restore("test", OrderBookState::default(), events);
+        assert_eq!(restored.state(), order_book.state());
+    }
+
+    #[test]
+    fn test_trade_is_executed_at_maker_price() {
         let mut order_book = OrderBook::new("test");
         let events = order_book.process(Command::Buy {
             quantity: 5,
             price: dec!(2),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
         });
-        let [Event::Accepted { ts: _, order: buy_order}] = &events[..] else {
+        let [Event::Accepted { ts: _, order: maker }] = &events[..] else {
             panic!("Wrong events={:?}", events);
         };
-        assert_eq!(buy_order.order_type, OrderType::Buy);
+        let maker = maker.clone();
         let events = order_book.process(Command::Sell {
-            quantity: 10,
-            price: dec!(2),
+            quantity: 3,
+            price: dec!(1),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
         });
-        let [
-            Event::Accepted {
-                ts:_,
-                order: sell_order
-            },
-            Event::Filled {
+        let [Event::Accepted { ts: _, order: taker }, Event::Filled { ts, trade, .. }] = &events[..]
+        else {
+            panic!("Wrong events={:?}", events);
+        };
+        assert_eq!(trade.ts, *ts);
+        assert_eq!(trade.price, dec!(2));
+        assert_eq!(trade.quantity, 3);
+        assert_eq!(trade.maker_order_id, maker.id);
+        assert_eq!(trade.taker_order_id, taker.id);
+        assert_eq!(trade.aggressor_side, OrderType::Sell);
+        assert_eq!(trade.maker_remaining_quantity, 2);
+        assert_eq!(trade.taker_remaining_quantity, 0);
+    }
+
+    #[test]
+    fn test_buy_sweeps_asks_up_to_its_price_at_maker_prices() {
+        let mut order_book = OrderBook::new("test");
+        for price in [dec!(2), dec!(2.5), dec!(3.5)] {
+            order_book.process(Command::Sell {
+                quantity: 2,
+                price,
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                owner: None,
+            });
+        }
+        let events = order_book.process(Command::Buy {
+            quantity: 5,
+            price: dec!(3),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
+        });
+        let fills: Vec<(Decimal, u32)> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Filled { trade, .. } => Some((trade.price, trade.quantity)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(fills, vec![(dec!(2), 2), (dec!(2.5), 2)]);
+        let state = order_book.state();
+        assert_eq!(state.buy.len(), 1);
+        assert_eq!((state.buy[0].price, state.buy[0].quantity), (dec!(3), 1));
+        assert_eq!(state.sell.len(), 1);
+        assert_eq!(state.sell[0].price, dec!(3.5));
+        assert_eq!(state.last_price, Some(dec!(2.5)));
+    }
+
+    #[test]
+    fn test_sell_sweeps_bids_down_to_its_price_at_maker_prices() {
+        let mut order_book = OrderBook::new("test");
+        for price in [dec!(3), dec!(2.5), dec!(1.5)] {
+            order_book.process(Command::Buy {
+                quantity: 2,
+                price,
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                owner: None,
+            });
+        }
+        assert_eq!(order_book.state().sell.len(), 0);
+        let events = order_book.process(Command::Sell {
+            quantity: 5,
+            price: dec!(2),
+            time_in_force: TimeInForce::ImmediateOrCancel,
+            post_only: None,
+            owner: None,
+        });
+        let fills: Vec<(Decimal, u32)> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Filled { trade, .. } => Some((trade.price, trade.quantity)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(fills, vec![(dec!(3), 2), (dec!(2.5), 2)]);
+        assert!(matches!(events.last(), Some(Event::Canceled { .. })));
+        let state = order_book.state();
+        assert!(state.sell.is_empty());
+        assert_eq!(state.buy.len(), 1);
+        assert_eq!(state.buy[0].price, dec!(1.5));
+    }
+
+    #[test]
+    fn test_depth_aggregates_orders_by_price_level() {
+        let mut order_book = OrderBook::new("test");
+        for (quantity, price) in [(5, dec!(2)), (3, dec!(1)), (2, dec!(2)), (1, dec!(0.5))] {
+            order_book.process(Command::Buy {
+                quantity,
+                price,
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                owner: None,
+            });
+        }
+        order_book.process(Command::SellIceberg {
+            quantity: 10,
+            display_quantity: 4,
+            price: dec!(3),
+            time_in_force: TimeInForce::GoodTillCancel,
+        });
+
+        let events = order_book.process(Command::GetDepth { levels: 2 });
+        let [Event::Depth { depth }] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        let level = |price, quantity, order_count| PriceLevel {
+            price,
+            quantity,
+            order_count,
+        };
+        assert_eq!(depth.buy, vec![level(dec!(2), 7, 2), level(dec!(1), 3, 1)]);
+        assert_eq!(depth.sell, vec![level(dec!(3), 4, 1)]);
+        assert!(order_book.depth(0).buy.is_empty());
+        assert_eq!(
+            order_book.price_level(OrderType::Buy, dec!(0.5)),
+            level(dec!(0.5), 1, 1)
+        );
+        assert_eq!(
+            order_book.price_level(OrderType::Sell, dec!(2)),
+            level(dec!(2), 0, 0)
+        );
+    }
+
+    #[test]
+    fn test_fill_buy_order_leaving_leftovers() {
+        let mut order_book = OrderBook::new("test");
+        let events = order_book.process(Command::Buy {
+            quantity: 5,
+            price: dec!(2),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
+        });
+        let [Event::Accepted { ts: _, order: buy_order}] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        assert_eq!(buy_order.order_type, OrderType::Buy);
+        let events = order_book.process(Command::Sell {
+            quantity: 10,
+            price: dec!(2),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
+        });
+        let [
+            Event::Accepted {
+                ts:_,
+                order: sell_order
+            },
+            Event::Filled {
                 ts:_,
                 order: filled_order,
-                counterpart
+                counterpart,
+                ..
             },
             Event::Accepted {
                 ts:_,
@@ -539,12 +2356,1034 @@ mod tests {
         assert_eq!(order_book.sell_book.len(), 1);
     }
 
+    #[test]
+    fn test_market_buy_sweeps_sell_book() {
+        let mut order_book = OrderBook::new("test");
+        order_book.process(Command::Sell {
+            quantity: 5,
+            price: dec!(2),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
+        });
+        order_book.process(Command::Sell {
+            quantity: 5,
+            price: dec!(3),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
+        });
+        let events = order_book.process(Command::MarketBuy { quantity: 7 });
+        let [
+            Event::Accepted { ts: _, order },
+            Event::Filled { ts: _, order: _, counterpart: first, .. },
+            Event::Accepted { ts: _, order: remaining },
+            Event::Filled { ts: _, order: _, counterpart: second, .. },
+        ] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        assert_eq!(order.kind, OrderKind::Market);
+        assert_eq!(remaining.quantity, 2);
+        assert_eq!(first.price, dec!(2));
+        assert_eq!(second.price, dec!(3));
+        assert!(order_book.buy_book.is_empty());
+        assert_eq!(order_book.sell_book.len(), 1);
+        assert_eq!(order_book.sell_book.first().unwrap().quantity, 3);
+    }
+
+    #[test]
+    fn test_market_sell_cancels_remainder_when_book_is_exhausted() {
+        let mut order_book = OrderBook::new("test");
+        order_book.process(Command::Buy {
+            quantity: 5,
+            price: dec!(2),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
+        });
+        let events = order_book.process(Command::MarketSell { quantity: 8 });
+        let [
+            Event::Accepted { ts: _, order },
+            Event::Filled { .. },
+            Event::Accepted { .. },
+            Event::Canceled { ts: _, order: canceled },
+        ] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        assert_eq!(canceled.id, order.id);
+        assert_eq!(canceled.quantity, 3);
+        assert!(order_book.buy_book.is_empty());
+        assert!(order_book.sell_book.is_empty());
+        assert!(order_book.sell_index.is_empty());
+    }
+
+    #[test]
+    fn test_immediate_or_cancel_cancels_remainder() {
+        let mut order_book = OrderBook::new("test");
+        order_book.process(Command::Sell {
+            quantity: 5,
+            price: dec!(2),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
+        });
+        let events = order_book.process(Command::Buy {
+            quantity: 8,
+            price: dec!(2),
+            time_in_force: TimeInForce::ImmediateOrCancel,
+            post_only: None,
+            owner: None,
+        });
+        let [
+            Event::Accepted { .. },
+            Event::Filled { .. },
+            Event::Accepted { .. },
+            Event::Canceled { ts: _, order: canceled },
+        ] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        assert_eq!(canceled.time_in_force, TimeInForce::ImmediateOrCancel);
+        assert_eq!(canceled.quantity, 3);
+        assert!(order_book.buy_book.is_empty());
+        assert!(order_book.sell_book.is_empty());
+    }
+
+    #[test]
+    fn test_fill_or_kill_is_rejected_without_touching_the_book() {
+        let mut order_book = OrderBook::new("test");
+        order_book.process(Command::Sell {
+            quantity: 5,
+            price: dec!(2),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
+        });
+        let before = order_book.state();
+        let events = order_book.process(Command::Buy {
+            quantity: 8,
+            price: dec!(2),
+            time_in_force: TimeInForce::FillOrKill,
+            post_only: None,
+            owner: None,
+        });
+        let [Event::Rejected { .. }] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        assert_eq!(order_book.state(), before);
+
+        let events = order_book.process(Command::Buy {
+            quantity: 5,
+            price: dec!(2),
+            time_in_force: TimeInForce::FillOrKill,
+            post_only: None,
+            owner: None,
+        });
+        let [Event::Accepted { .. }, Event::Filled { .. }] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        assert!(order_book.sell_book.is_empty());
+        assert!(order_book.buy_book.is_empty());
+    }
+
+    #[test]
+    fn test_day_order_expires_after_session_end() {
+        let mut order_book = OrderBook::new("test");
+        let yesterday = Utc::now() - Duration::days(1);
+        let day_order = Order {
+            time_in_force: TimeInForce::Day,
+            ..Order::buy(Uuid::new_v4(), yesterday, 5, dec!(2))
+        };
+        order_book.apply(&Event::Accepted {
+            ts: yesterday,
+            order: day_order.clone(),
+        });
+        order_book.apply(&Event::Accepted {
+            ts: yesterday,
+            order: Order::buy(Uuid::new_v4(), yesterday, 5, dec!(1)),
+        });
+        let events = order_book.process(Command::Sell {
+            quantity: 1,
+            price: dec!(3),
+            time_in_force: TimeInForce::Day,
+            post_only: None,
+            owner: None,
+        });
+        let [Event::Expired { ts: _, order }, Event::Accepted { .. }] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        assert_eq!(order.id, day_order.id);
+        let events = order_book.process(Command::GetState);
+        let [Event::State { state }] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        assert_eq!(state.buy.len(), 1);
+        assert_eq!(state.buy[0].price, dec!(1));
+        assert_eq!(state.sell.len(), 1);
+        assert_eq!(state.sell[0].time_in_force, TimeInForce::Day);
+    }
+
+    #[test]
+    fn test_day_order_expiration_is_emitted_once() {
+        let mut order_book = OrderBook::new("test");
+        let yesterday = Utc::now() - Duration::days(1);
+        let day_order = Order {
+            time_in_force: TimeInForce::Day,
+            ..Order::sell(Uuid::new_v4(), yesterday, 5, dec!(2))
+        };
+        order_book.apply(&Event::Accepted {
+            ts: yesterday,
+            order: day_order.clone(),
+        });
+        let events = order_book.process(Command::GetState);
+        let [Event::Expired { ts: _, order }, Event::State { .. }] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        assert_eq!(order.id, day_order.id);
+        let events = order_book.process(Command::GetState);
+        assert!(matches!(&events[..], [Event::State { .. }]));
+    }
+
+    fn replay(commands: impl Fn() -> Vec<Command>) -> Vec<Event> {
+        let start = Utc.with_ymd_and_hms(2023, 6, 1, 12, 0, 0).unwrap();
+        let clock = Rc::new(ManualClock::new(start));
+        let mut order_book = OrderBook::new("test")
+            .with_clock(clock.clone())
+            .with_ids(SequentialIds::default());
+        let mut events = vec![];
+        for command in commands() {
+            events.extend(order_book.process(command));
+            clock.advance(Duration::seconds(1));
+        }
+        events
+    }
+
+    #[test]
+    fn test_processing_is_deterministic_with_a_manual_clock_and_sequential_ids() {
+        let gtc = TimeInForce::GoodTillCancel;
+        let commands = || {
+            vec![
+                Command::Buy {
+                    quantity: 5,
+                    price: dec!(2),
+                    time_in_force: gtc,
+                    post_only: None,
+                    owner: None,
+                },
+                Command::SellIceberg {
+                    quantity: 8,
+                    display_quantity: 2,
+                    price: dec!(1.5),
+                    time_in_force: gtc,
+                },
+                Command::MarketBuy { quantity: 2 },
+                Command::Update {
+                    id: Uuid::from_u128(2),
+                    side: None,
+                    new_quantity: 4,
+                    new_price: dec!(1.5),
+                },
+                Command::Buy {
+                    quantity: 1,
+                    price: dec!(1.5),
+                    time_in_force: gtc,
+                    post_only: None,
+                    owner: None,
+                },
+            ]
+        };
+        let events = replay(commands);
+        assert_eq!(
+            serde_json::to_string(&events).unwrap(),
+            serde_json::to_string(&replay(commands)).unwrap()
+        );
+        let trades: Vec<(Uuid, DateTime<Utc>, Uuid, Uuid)> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Filled { trade, .. } => Some((
+                    trade.id,
+                    trade.ts,
+                    trade.maker_order_id,
+                    trade.taker_order_id,
+                )),
+                _ => None,
+            })
+            .collect();
+        let start = Utc.with_ymd_and_hms(2023, 6, 1, 12, 0, 0).unwrap();
+        let id = Uuid::from_u128;
+        assert_eq!(
+            trades,
+            vec![
+                (id(3), start + Duration::seconds(1), id(1), id(2)),
+                (id(5), start + Duration::seconds(2), id(2), id(4)),
+                (id(7), start + Duration::seconds(4), id(2), id(6)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_day_order_expires_when_the_clock_passes_session_end() {
+        let start = Utc.with_ymd_and_hms(2023, 6, 1, 12, 0, 0).unwrap();
+        let clock = Rc::new(ManualClock::new(start));
+        let mut order_book = OrderBook::new("test")
+            .with_session_end(NaiveTime::from_hms_opt(17, 0, 0).unwrap())
+            .with_clock(clock.clone());
+        let events = order_book.process(Command::Buy {
+            quantity: 5,
+            price: dec!(2),
+            time_in_force: TimeInForce::Day,
+            post_only: None,
+            owner: None,
+        });
+        let [Event::Accepted { order, .. }] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        let day_order_id = order.id;
+        clock.advance(Duration::hours(4));
+        assert_eq!(order_book.process(Command::GetState).len(), 1);
+        clock.advance(Duration::hours(1));
+        let events = order_book.process(Command::GetState);
+        let [Event::Expired { ts, order }, Event::State { .. }] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        assert_eq!(order.id, day_order_id);
+        assert_eq!(*ts, start + Duration::hours(5));
+    }
+
+    #[test]
+    fn test_buy_stop_triggers_market_order_on_last_trade_price() {
+        let mut order_book = OrderBook::new("test");
+        order_book.process(Command::Sell {
+            quantity: 5,
+            price: dec!(10),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
+        });
+        order_book.process(Command::Sell {
+            quantity: 5,
+            price: dec!(12),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
+        });
+        let events = order_book.process(Command::BuyStop {
+            quantity: 3,
+            trigger_price: dec!(10),
+            limit_price: None,
+            time_in_force: TimeInForce::GoodTillCancel,
+        });
+        let [Event::Accepted { ts: _, order: stop }] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        assert_eq!(stop.trigger_price, Some(dec!(10)));
+        assert_eq!(order_book.state().stop.len(), 1);
+
+        let events = order_book.process(Command::MarketBuy { quantity: 1 });
+        let [
+            Event::Accepted { .. },
+            Event::Filled { .. },
+            Event::Triggered { ts: _, order: triggered },
+            Event::Accepted { ts: _, order },
+            Event::Filled { ts: _, order: _, counterpart, .. },
+        ] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        assert_eq!(triggered.id, stop.id);
+        assert_eq!(order.id, stop.id);
+        assert_eq!(order.kind, OrderKind::Market);
+        assert_eq!(order.trigger_price, None);
+        assert_eq!(counterpart.price, dec!(10));
+        let state = order_book.state();
+        assert!(state.stop.is_empty());
+        assert_eq!(state.last_price, Some(dec!(10)));
+        assert_eq!(state.sell[0].quantity, 1);
+    }
+
+    #[test]
+    fn test_sell_stop_limit_rests_once_triggered() {
+        let mut order_book = OrderBook::new("test");
+        let events = order_book.process(Command::SellStop {
+            quantity: 2,
+            trigger_price: dec!(9),
+            limit_price: Some(dec!(11)),
+            time_in_force: TimeInForce::GoodTillCancel,
+        });
+        let [Event::Accepted { ts: _, order: stop }] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        order_book.process(Command::Buy {
+            quantity: 5,
+            price: dec!(10),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
+        });
+        let events = order_book.process(Command::MarketSell { quantity: 1 });
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, Event::Triggered { .. })));
+
+        order_book.process(Command::Buy {
+            quantity: 5,
+            price: dec!(9),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
+        });
+        order_book.process(Command::MarketSell { quantity: 4 });
+        let events = order_book.process(Command::MarketSell { quantity: 1 });
+        let [
+            Event::Accepted { .. },
+            Event::Filled { .. },
+            Event::Triggered { ts: _, order: triggered },
+            Event::Accepted { ts: _, order },
+        ] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        assert_eq!(triggered.id, stop.id);
+        assert_eq!(order.price, dec!(11));
+        let state = order_book.state();
+        assert!(state.stop.is_empty());
+        assert_eq!(state.sell.len(), 1);
+        assert_eq!(state.sell[0].id, stop.id);
+    }
+
+    #[test]
+    fn test_cancel_stop_order() {
+        let mut order_book = OrderBook::new("test");
+        let events = order_book.process(Command::BuyStop {
+            quantity: 3,
+            trigger_price: dec!(10),
+            limit_price: Some(dec!(11)),
+            time_in_force: TimeInForce::GoodTillCancel,
+        });
+        let [Event::Accepted { ts: _, order: stop }] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        let events = order_book.process(Command::Cancel {
+            id: stop.id,
+            side: None,
+        });
+        let [Event::Canceled { ts: _, order }] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        assert_eq!(order.id, stop.id);
+        assert!(order_book.state().stop.is_empty());
+    }
+
+    #[test]
+    fn test_cancel_all_orders() {
+        let mut order_book = OrderBook::new("test");
+        for price in [dec!(1), dec!(2)] {
+            order_book.process(Command::Buy {
+                quantity: 1,
+                price,
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                owner: None,
+            });
+        }
+        order_book.process(Command::SellIceberg {
+            quantity: 10,
+            display_quantity: 2,
+            price: dec!(3),
+            time_in_force: TimeInForce::GoodTillCancel,
+        });
+        order_book.process(Command::SellStop {
+            quantity: 1,
+            trigger_price: dec!(1),
+            limit_price: None,
+            time_in_force: TimeInForce::GoodTillCancel,
+        });
+
+        let events = order_book.process(Command::CancelAll);
+        let canceled: Vec<(OrderType, Decimal)> = events
+            .iter()
+            .map(|event| match event {
+                Event::Canceled { order, .. } => (order.order_type, order.price),
+                _ => panic!("Wrong events={:?}", events),
+            })
+            .collect();
+        assert_eq!(
+            canceled,
+            vec![
+                (OrderType::Sell, dec!(3)),
+                (OrderType::Buy, dec!(2)),
+                (OrderType::Buy, dec!(1)),
+                (OrderType::Sell, Decimal::ZERO),
+            ]
+        );
+        assert_eq!(order_book.state(), OrderBookState::default());
+        assert!(order_book.process(Command::CancelAll).is_empty());
+    }
+
+    #[test]
+    fn test_iceberg_order_shows_only_display_quantity() {
+        let mut order_book = OrderBook::new("test");
+        let events = order_book.process(Command::BuyIceberg {
+            quantity: 10,
+            display_quantity: 3,
+            price: dec!(2),
+            time_in_force: TimeInForce::GoodTillCancel,
+        });
+        let [Event::Accepted { ts: _, order }] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        assert_eq!(order.quantity, 10);
+        let state = order_book.state();
+        assert_eq!(state.buy.len(), 1);
+        assert_eq!(state.buy[0].quantity, 3);
+        assert_eq!(state.buy[0].reserve_quantity, 7);
+        let json = serde_json::to_value(&state).unwrap();
+        assert!(json["buy"][0].get("reserve_quantity").is_none());
+
+        let events = order_book.process(Command::SellIceberg {
+            quantity: 10,
+            display_quantity: 0,
+            price: dec!(2),
+            time_in_force: TimeInForce::GoodTillCancel,
+        });
+        let [Event::Rejected { .. }] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+    }
+
+    #[test]
+    fn test_incoming_iceberg_order_trades_show_only_display_quantity() {
+        let mut order_book = OrderBook::new("test");
+        order_book.process(Command::Sell {
+            quantity: 1,
+            price: dec!(5),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
+        });
+        let events = order_book.process(Command::BuyIceberg {
+            quantity: 7,
+            display_quantity: 2,
+            price: dec!(5),
+            time_in_force: TimeInForce::GoodTillCancel,
+        });
+        let trades: Vec<&Trade> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Filled { trade, .. } => Some(trade),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].quantity, 1);
+        assert_eq!(trades[0].taker_remaining_quantity, 2);
+        assert_eq!(order_book.state().buy[0].quantity, 2);
+    }
+
+    #[test]
+    fn test_iceberg_order_replenishes_with_fresh_priority() {
+        let mut order_book = OrderBook::new("test");
+        let events = order_book.process(Command::BuyIceberg {
+            quantity: 5,
+            display_quantity: 3,
+            price: dec!(2),
+            time_in_force: TimeInForce::GoodTillCancel,
+        });
+        let [Event::Accepted { ts: _, order: iceberg }] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        let iceberg = iceberg.clone();
+        let events = order_book.process(Command::Buy {
+            quantity: 1,
+            price: dec!(2),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
+        });
+        let [Event::Accepted { ts: _, order: other }] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        let other = other.clone();
+
+        let events = order_book.process(Command::MarketSell { quantity: 3 });
+        let [
+            Event::Accepted { .. },
+            Event::Filled { ts: _, order: _, counterpart, .. },
+            Event::Replenished { ts, order: slice },
+        ] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        assert_eq!(counterpart.id, iceberg.id);
+        assert_eq!(slice.id, iceberg.id);
+        assert_eq!(slice.ts, *ts);
+        assert_eq!(slice.quantity, 2);
+        assert_eq!(slice.reserve_quantity, 0);
+        let ids: Vec<Uuid> = order_book
+            .state()
+            .buy
+            .iter()
+            .map(|order| order.id)
+            .collect();
+        assert_eq!(ids, vec![other.id, iceberg.id]);
+
+        let events = order_book.process(Command::MarketSell { quantity: 3 });
+        let filled: Vec<Uuid> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Filled { counterpart, .. } => Some(counterpart.id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(filled, vec![other.id, iceberg.id]);
+        let Some(Event::Exhausted { ts: _, order: exhausted }) = events.last() else {
+            panic!("Wrong events={:?}", events);
+        };
+        assert_eq!(exhausted.id, iceberg.id);
+        assert_eq!(exhausted.quantity, 0);
+        assert!(order_book.state().buy.is_empty());
+    }
+
+    #[test]
+    fn test_restore_iceberg_orders_from_events() {
+        let mut order_book = OrderBook::new("test");
+        let commands = vec![
+            Command::BuyIceberg {
+                quantity: 4,
+                display_quantity: 1,
+                price: dec!(0.5),
+                time_in_force: TimeInForce::GoodTillCancel,
+            },
+            Command::Buy {
+                quantity: 2,
+                price: dec!(2),
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                owner: None,
+            },
+            Command::SellIceberg {
+                quantity: 10,
+                display_quantity: 3,
+                price: dec!(1),
+                time_in_force: TimeInForce::GoodTillCancel,
+            },
+            Command::MarketBuy { quantity: 4 },
+        ];
+        let mut events = vec![];
+        for command in commands {
+            events.extend(order_book.process(command));
+        }
+        let state = order_book.state();
+        assert_eq!(state.sell[0].quantity, 2);
+        assert_eq!(state.sell[0].reserve_quantity, 2);
+        assert_eq!(state.buy[0].quantity, 1);
+        assert_eq!(state.buy[0].reserve_quantity, 3);
+
+        let restored = OrderBook::restore("test", OrderBookState::default(), events);
+        assert_eq!(restored.state(), state);
+    }
+
+    #[test]
+    fn test_post_only_order_is_rejected_instead_of_taking_liquidity() {
+        let mut order_book = OrderBook::new("test");
+        order_book.process(Command::Buy {
+            quantity: 5,
+            price: dec!(2),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
+        });
+        let events = order_book.process(Command::Sell {
+            quantity: 5,
+            price: dec!(2),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: Some(PostOnly::Reject),
+            owner: None,
+        });
+        let [Event::Rejected { .. }] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        assert_eq!(order_book.state().buy[0].quantity, 5);
+        assert!(order_book.state().sell.is_empty());
+
+        let events = order_book.process(Command::Sell {
+            quantity: 5,
+            price: dec!(3),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: Some(PostOnly::Reject),
+            owner: None,
+        });
+        let [Event::Accepted { ts: _, order }] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        assert_eq!(order.post_only, Some(PostOnly::Reject));
+        assert_eq!(order_book.state().sell.len(), 1);
+    }
+
+    #[test]
+    fn test_post_only_order_is_repriced_one_tick_away() {
+        let mut order_book = OrderBook::new("test").with_tick_size(dec!(0.05));
+        order_book.process(Command::Buy {
+            quantity: 5,
+            price: dec!(2),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
+        });
+        let events = order_book.process(Command::Sell {
+            quantity: 3,
+            price: dec!(1.5),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: Some(PostOnly::Reprice),
+            owner: None,
+        });
+        let [Event::Accepted { ts: _, order }] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        assert_eq!(order.price, dec!(2.05));
+        let state = order_book.state();
+        assert_eq!(state.buy[0].quantity, 5);
+        assert_eq!(state.sell[0].price, dec!(2.05));
+    }
+
+    #[test]
+    fn test_orders_breaking_instrument_rules_are_rejected() {
+        let mut order_book = OrderBook::new("test")
+            .with_tick_size(dec!(0.05))
+            .with_rules(InstrumentRules {
+                lot_size: 10,
+                min_quantity: Some(20),
+                max_quantity: Some(1000),
+                min_notional: Some(dec!(50)),
+                min_price: Some(dec!(1)),
+                max_price: Some(dec!(100)),
+                price_band: Some(dec!(0.1)),
+            });
+        let sell = |order_book: &mut OrderBook, quantity, price| {
+            let events = order_book.process(Command::Sell {
+                quantity,
+                price,
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                owner: None,
+            });
+            match &events[..] {
+                [Event::Rejected { code, .. }] => Some(*code),
+                _ => None,
+            }
+        };
+        assert_eq!(
+            sell(&mut order_book, 0, dec!(10)),
+            Some(RejectionCode::InvalidQuantity)
+        );
+        assert_eq!(
+            sell(&mut order_book, 25, dec!(10)),
+            Some(RejectionCode::LotSize)
+        );
+        assert_eq!(
+            sell(&mut order_book, 10, dec!(10)),
+            Some(RejectionCode::MinQuantity)
+        );
+        assert_eq!(
+            sell(&mut order_book, 1010, dec!(10)),
+            Some(RejectionCode::MaxQuantity)
+        );
+        assert_eq!(
+            sell(&mut order_book, 20, dec!(-1)),
+            Some(RejectionCode::InvalidPrice)
+        );
+        assert_eq!(
+            sell(&mut order_book, 20, dec!(10.02)),
+            Some(RejectionCode::TickSize)
+        );
+        assert_eq!(
+            sell(&mut order_book, 20, dec!(0.5)),
+            Some(RejectionCode::MinPrice)
+        );
+        assert_eq!(
+            sell(&mut order_book, 20, dec!(100.5)),
+            Some(RejectionCode::MaxPrice)
+        );
+        assert_eq!(
+            sell(&mut order_book, 20, dec!(2)),
+            Some(RejectionCode::MinNotional)
+        );
+        // Without a last trade the price is not banded.
+        assert_eq!(sell(&mut order_book, 20, dec!(50)), None);
+        assert_eq!(sell(&mut order_book, 20, dec!(10)), None);
+
+        let events = order_book.process(Command::MarketBuy { quantity: 20 });
+        assert!(matches!(events.last(), Some(Event::Filled { .. })));
+        assert_eq!(order_book.last_price, Some(dec!(10)));
+        assert_eq!(
+            sell(&mut order_book, 20, dec!(11.05)),
+            Some(RejectionCode::PriceBand)
+        );
+        assert_eq!(sell(&mut order_book, 20, dec!(11)), None);
+        let events = order_book.process(Command::MarketBuy { quantity: 15 });
+        let [Event::Rejected { code, .. }] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        assert_eq!(*code, RejectionCode::LotSize);
+
+        let events = order_book.process(Command::BuyIceberg {
+            quantity: 100,
+            display_quantity: 15,
+            price: dec!(9),
+            time_in_force: TimeInForce::GoodTillCancel,
+        });
+        let [Event::Rejected { code, .. }] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        assert_eq!(*code, RejectionCode::LotSize);
+
+        let id = order_book.state().sell[0].id;
+        let events = order_book.process(Command::Update {
+            id,
+            side: None,
+            new_quantity: 20,
+            new_price: dec!(8.95),
+        });
+        let [Event::Rejected { code, .. }] = &events[..] else {
+            panic!("Wrong events={:?}", events);
+        };
+        assert_eq!(*code, RejectionCode::PriceBand);
+    }
+    #[test]
+    fn test_self_trade_prevention_policies() {
+        let buy = |quantity, owner: &str| Command::Buy {
+            quantity,
+            price: dec!(2),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: Some(owner.to_owned()),
+        };
+        let cases = [
+            (SelfTradePrevention::CancelNewest, vec![5, 1], vec![]),
+            (SelfTradePrevention::CancelOldest, vec![], vec![2]),
+            (SelfTradePrevention::CancelBoth, vec![1], vec![]),
+            (SelfTradePrevention::DecrementAndCancel, vec![2, 1], vec![]),
+        ];
+        for (policy, buy_quantities, sell_quantities) in cases {
+            let mut order_book = OrderBook::new("test").with_self_trade_prevention(policy);
+            let mut events = order_book.process(buy(5, "a"));
+            events.extend(order_book.process(buy(1, "b")));
+            let sell_events = order_book.process(Command::Sell {
+                quantity: 3,
+                price: dec!(2),
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                owner: Some("a".to_owned()),
+            });
+            let prevented = sell_events.iter().any(|event| match policy {
+                SelfTradePrevention::CancelNewest => {
+                    matches!(event, Event::SelfTradeCanceledNewest { .. })
+                }
+                SelfTradePrevention::CancelOldest => {
+                    matches!(event, Event::SelfTradeCanceledOldest { .. })
+                }
+                SelfTradePrevention::CancelBoth => {
+                    matches!(event, Event::SelfTradeCanceledBoth { .. })
+                }
+                SelfTradePrevention::DecrementAndCancel => {
+                    matches!(event, Event::SelfTradeDecremented { .. })
+                }
+            });
+            assert!(prevented, "Wrong events={:?}", sell_events);
+            let filled = sell_events.iter().any(|event| match event {
+                Event::Filled { counterpart, .. } => counterpart.owner.as_deref() == Some("a"),
+                _ => false,
+            });
+            assert!(!filled, "Wrong events={:?}", sell_events);
+
+            let state = order_book.state();
+            let quantities = |orders: &[Order]| -> Vec<u32> {
+                orders.iter().map(|order| order.quantity).collect()
+            };
+            assert_eq!(quantities(&state.buy), buy_quantities, "{:?}", policy);
+            assert_eq!(quantities(&state.sell), sell_quantities, "{:?}", policy);
+
+            events.extend(sell_events);
+            let restored = OrderBook::restore("test", OrderBookState::default(), events);
+            assert_eq!(restored.state(), state);
+        }
+    }
+
+    #[test]
+    fn test_fill_or_kill_is_rejected_when_self_trade_prevention_stops_it() {
+        let cases = [
+            (SelfTradePrevention::CancelNewest, false),
+            (SelfTradePrevention::CancelOldest, true),
+            (SelfTradePrevention::CancelBoth, false),
+            (SelfTradePrevention::DecrementAndCancel, false),
+        ];
+        for (policy, fills) in cases {
+            let mut order_book = OrderBook::new("test").with_self_trade_prevention(policy);
+            let sells = [(6, dec!(1), "b"), (5, dec!(1.5), "a"), (4, dec!(2), "b")];
+            for (quantity, price, owner) in sells {
+                order_book.process(Command::Sell {
+                    quantity,
+                    price,
+                    time_in_force: TimeInForce::GoodTillCancel,
+                    post_only: None,
+                    owner: Some(owner.to_owned()),
+                });
+            }
+            let state = order_book.state();
+            let events = order_book.process(Command::Buy {
+                quantity: 10,
+                price: dec!(2),
+                time_in_force: TimeInForce::FillOrKill,
+                post_only: None,
+                owner: Some("a".to_owned()),
+            });
+            let filled: u32 = events
+                .iter()
+                .filter_map(|event| match event {
+                    Event::Filled { trade, .. } => Some(trade.quantity),
+                    _ => None,
+                })
+                .sum();
+            if fills {
+                assert_eq!(filled, 10, "{:?}", policy);
+                assert!(order_book.state().sell.is_empty(), "{:?}", policy);
+            } else {
+                assert!(
+                    matches!(
+                        events[..],
+                        [Event::Rejected {
+                            code: RejectionCode::FillOrKillNotFilled,
+                            ..
+                        }]
+                    ),
+                    "{:?}: Wrong events={:?}",
+                    policy,
+                    events
+                );
+                assert_eq!(order_book.state(), state, "{:?}", policy);
+            }
+        }
+    }
+
+    #[test]
+    fn test_restore_from_events() {
+        let mut order_book = OrderBook::new("test");
+        let commands = vec![
+            Command::Buy {
+                quantity: 5,
+                price: dec!(2),
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                owner: None,
+            },
+            Command::Buy {
+                quantity: 3,
+                price: dec!(1.5),
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                owner: None,
+            },
+            Command::Buy {
+                quantity: 4,
+                price: dec!(1),
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                owner: None,
+            },
+            Command::Sell {
+                quantity: 10,
+                price: dec!(4),
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                owner: None,
+            },
+            Command::Sell {
+                quantity: 2,
+                price: dec!(2),
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                owner: None,
+            },
+            Command::Sell {
+                quantity: 4,
+                price: dec!(2),
+                time_in_force: TimeInForce::GoodTillCancel,
+                post_only: None,
+                owner: None,
+            },
+            Command::MarketSell { quantity: 1 },
+            Command::SellStop {
+                quantity: 1,
+                trigger_price: dec!(1.5),
+                limit_price: None,
+                time_in_force: TimeInForce::GoodTillCancel,
+            },
+            Command::BuyStop {
+                quantity: 1,
+                trigger_price: dec!(5),
+                limit_price: Some(dec!(6)),
+                time_in_force: TimeInForce::GoodTillCancel,
+            },
+        ];
+        let mut events = vec![];
+        for command in commands {
+            events.extend(order_book.process(command));
+        }
+        let id = order_book.sell_book.first().unwrap().id;
+        events.extend(order_book.process(Command::Update {
+            id,
+            side: None,
+            new_quantity: 4,
+            new_price: dec!(3),
+        }));
+        let id = order_book.buy_book.first().unwrap().id;
+        events.extend(order_book.process(Command::Cancel { id, side: None }));
+
+        let restored = OrderBook::restore("test", OrderBookState::default(), events);
+        assert_eq!(restored.state(), order_book.state());
+        assert_eq!(restored.buy_index.len(), restored.buy_book.len());
+        assert_eq!(restored.sell_index.len(), restored.sell_book.len());
+    }
+
+    #[test]
+    fn test_restore_from_state_and_events() {
+        let mut order_book = OrderBook::new("test");
+        order_book.process(Command::Buy {
+            quantity: 5,
+            price: dec!(2),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
+        });
+        order_book.process(Command::Sell {
+            quantity: 10,
+            price: dec!(4),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
+        });
+        let state = order_book.state();
+        let mut events = order_book.process(Command::Sell {
+            quantity: 2,
+            price: dec!(2),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
+        });
+        let id = order_book.sell_book.first().unwrap().id;
+        events.extend(order_book.process(Command::Cancel { id, side: None }));
+
+        let restored = OrderBook::restore("test", state, events);
+        assert_eq!(restored.state(), order_book.state());
+        assert_eq!(restored.buy_index.len(), 1);
+        assert!(restored.sell_index.is_empty());
+    }
+
     #[test]
     fn test_fill_sell_order_leaving_leftovers() {
         let mut order_book = OrderBook::new("test");
         let events = order_book.process(Command::Sell {
             quantity: 5,
             price: dec!(2),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
         });
         let [Event::Accepted { ts: _, order: buy_order}] = &events[..] else {
             panic!("Wrong events={:?}", events);
@@ -553,6 +3392,9 @@ mod tests {
         let events = order_book.process(Command::Buy {
             quantity: 10,
             price: dec!(2),
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            owner: None,
         });
         let [
             Event::Accepted {
@@ -562,7 +3404,8 @@ mod tests {
             Event::Filled {
                 ts:_,
                 order: filled_order,
-                counterpart
+                counterpart,
+                ..
             },
             Event::Accepted {
                 ts:_,
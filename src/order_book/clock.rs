@@ -0,0 +1,54 @@
+use std::{cell::Cell, fmt::Debug, rc::Rc};
+
+use chrono::{DateTime, Duration, Utc};
+
+/// Tells the time commands are processed at, the time stamp of the orders,
+/// trades and events they emit.
+pub trait Clock: Debug {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+impl<C: Clock + ?Sized> Clock for Rc<C> {
+    fn now(&self) -> DateTime<Utc> {
+        self.as_ref().now()
+    }
+}
+
+/// The system clock, the one used in production.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock standing still until moved, for tests, replays and simulations.
+/// Shared with an [`Rc`], it is moved while the order book uses it.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Cell<DateTime<Utc>>,
+}
+
+impl ManualClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        ManualClock {
+            now: Cell::new(now),
+        }
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        self.now.set(now);
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now.set(self.now.get() + duration);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.now.get()
+    }
+}
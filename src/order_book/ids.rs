@@ -0,0 +1,45 @@
+use std::fmt::Debug;
+
+use uuid::Uuid;
+
+/// Generates the ids of the orders placed and the trades done.
+pub trait IdGenerator: Debug {
+    fn next_id(&mut self) -> Uuid;
+}
+
+/// Random (version 4) ids, the ones used in production.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RandomIds;
+
+impl IdGenerator for RandomIds {
+    fn next_id(&mut self) -> Uuid {
+        Uuid::new_v4()
+    }
+}
+
+/// Ids counting up from a starting number, the same on every run, for tests,
+/// replays and simulations.
+#[derive(Debug, Clone, Copy)]
+pub struct SequentialIds {
+    next: u128,
+}
+
+impl SequentialIds {
+    pub fn starting_at(next: u128) -> Self {
+        SequentialIds { next }
+    }
+}
+
+impl Default for SequentialIds {
+    fn default() -> Self {
+        SequentialIds::starting_at(1)
+    }
+}
+
+impl IdGenerator for SequentialIds {
+    fn next_id(&mut self) -> Uuid {
+        let id = Uuid::from_u128(self.next);
+        self.next += 1;
+        id
+    }
+}
@@ -0,0 +1,459 @@
+//! The invariants an order book keeps between commands, verified by the
+//! tests after every command and, when enabled, by debug builds.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    rc::Rc,
+};
+
+use uuid::Uuid;
+
+use super::{Event, Order, OrderBook, OrderKind, OrderType};
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("{0}")]
+pub struct Violation(String);
+
+fn violation<T>(reason: String) -> Result<T, Violation> {
+    Err(Violation(reason))
+}
+
+/// Verifies the book is not crossed, the indexes mirror the books, no order
+/// is on both sides and every resting order has a positive quantity.
+pub fn verify(order_book: &OrderBook) -> Result<(), Violation> {
+    if let (Some(bid), Some(ask)) = (order_book.best_bid(), order_book.best_ask()) {
+        if bid >= ask {
+            return violation(format!("Book crossed, bid {} >= ask {}", bid, ask));
+        }
+    }
+    verify_side(
+        OrderType::Sell,
+        &order_book.sell_book,
+        &order_book.sell_index,
+    )?;
+    verify_side(OrderType::Buy, &order_book.buy_book, &order_book.buy_index)?;
+    if let Some(id) = order_book
+        .sell_index
+        .keys()
+        .find(|id| order_book.buy_index.contains_key(id))
+    {
+        return violation(format!("Order {} on both sides", id));
+    }
+    for (id, order) in &order_book.stop_index {
+        if order_book.sell_index.contains_key(id) || order_book.buy_index.contains_key(id) {
+            return violation(format!("Stop order {} also resting on the book", id));
+        }
+        if order.id != *id || order.trigger_price.is_none() || order.quantity == 0 {
+            return violation(format!("Invalid stop order {:?}", order));
+        }
+    }
+    Ok(())
+}
+
+fn verify_side(
+    side: OrderType,
+    book: &BTreeSet<Rc<Order>>,
+    index: &HashMap<Uuid, Rc<Order>>,
+) -> Result<(), Violation> {
+    if book.len() != index.len() {
+        return violation(format!(
+            "{:?} book has {} orders but its index {}",
+            side,
+            book.len(),
+            index.len()
+        ));
+    }
+    for order in book {
+        if !index
+            .get(&order.id)
+            .is_some_and(|indexed| Rc::ptr_eq(indexed, order))
+        {
+            return violation(format!("{:?} order {} not indexed", side, order.id));
+        }
+        if order.order_type != side {
+            return violation(format!(
+                "{:?} order {} on the {:?} book",
+                order.order_type, order.id, side
+            ));
+        }
+        if order.quantity == 0 {
+            return violation(format!(
+                "{:?} order {} rests without quantity",
+                side, order.id
+            ));
+        }
+        if order.kind != OrderKind::Limit || order.trigger_price.is_some() {
+            return violation(format!("{:?} order {} can not rest", side, order.id));
+        }
+    }
+    Ok(())
+}
+
+/// Verifies every fill takes the same quantity from both orders, the
+/// smallest of both, leaving them what they report as remaining.
+pub fn verify_fills(events: &[Event]) -> Result<(), Violation> {
+    for event in events {
+        let Event::Filled {
+            order,
+            counterpart,
+            trade,
+            ..
+        } = event
+        else {
+            continue;
+        };
+        let quantity = order.quantity.min(counterpart.quantity);
+        if quantity == 0
+            || trade.quantity != quantity
+            || trade.taker_remaining_quantity != order.shown(order.quantity - quantity)
+            || trade.maker_remaining_quantity + quantity != counterpart.quantity
+            || trade.price != counterpart.price
+        {
+            return violation(format!("Fill does not add up, trade={:?}", trade));
+        }
+    }
+    Ok(())
+}
+
+/// Follows the quantity of every order through the events emitted for it: the
+/// quantity left of an order is what it was placed, or amended, with less
+/// what it traded.
+#[derive(Debug, Default)]
+pub struct QuantityLedger {
+    /// Quantity placed, and taken since, by order.
+    orders: HashMap<Uuid, (u64, u64)>,
+}
+
+impl QuantityLedger {
+    pub fn record(&mut self, events: &[Event]) {
+        for event in events {
+            match event {
+                Event::Accepted { order, .. } => {
+                    self.orders.insert(order.id, (open_quantity(order), 0));
+                }
+                Event::Amended { order, .. } => {
+                    let (placed, taken) = self.orders.entry(order.id).or_default();
+                    *placed = *taken + open_quantity(order);
+                }
+                Event::Filled { trade, .. } => {
+                    self.take(trade.taker_order_id, trade.quantity);
+                    self.take(trade.maker_order_id, trade.quantity);
+                }
+                Event::SelfTradeDecremented {
+                    order, counterpart, ..
+                } => {
+                    let quantity = order.quantity.min(counterpart.quantity);
+                    self.take(order.id, quantity);
+                    self.take(counterpart.id, quantity);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn take(&mut self, id: Uuid, quantity: u32) {
+        self.orders.entry(id).or_default().1 += quantity as u64;
+    }
+
+    /// Verifies the orders of the book have the quantity left by the events
+    /// recorded.
+    pub fn verify(&self, order_book: &OrderBook) -> Result<(), Violation> {
+        let resting = order_book.sell_book.iter().chain(&order_book.buy_book);
+        let orders = resting
+            .map(|order| order.as_ref())
+            .chain(order_book.stop_index.values());
+        for order in orders {
+            let Some((placed, taken)) = self.orders.get(&order.id) else {
+                return violation(format!("Order {} never placed", order.id));
+            };
+            if placed.checked_sub(*taken) != Some(open_quantity(order)) {
+                return violation(format!(
+                    "Order {} placed {} and took {} but has {} left",
+                    order.id,
+                    placed,
+                    taken,
+                    open_quantity(order)
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The shown and the reserve quantity of an order.
+fn open_quantity(order: &Order) -> u64 {
+    order.quantity as u64 + order.reserve_quantity as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use rust_decimal::Decimal;
+
+    use super::*;
+    use crate::order_book::{Command, PostOnly, SelfTradePrevention, TimeInForce};
+
+    #[derive(Debug, Clone)]
+    enum Action {
+        Place {
+            side: OrderType,
+            quantity: u32,
+            ticks: i64,
+            time_in_force: TimeInForce,
+            post_only: Option<PostOnly>,
+            owner: Option<&'static str>,
+        },
+        Market {
+            side: OrderType,
+            quantity: u32,
+        },
+        Stop {
+            side: OrderType,
+            quantity: u32,
+            trigger_ticks: i64,
+            limit_ticks: Option<i64>,
+        },
+        Iceberg {
+            side: OrderType,
+            quantity: u32,
+            display_quantity: u32,
+            ticks: i64,
+        },
+        /// Amends one of the orders placed so far.
+        Update {
+            order: usize,
+            quantity: u32,
+            ticks: i64,
+        },
+        /// Cancels one of the orders placed so far, of the given side if any.
+        Cancel {
+            order: usize,
+            side: Option<OrderType>,
+        },
+        CancelAll,
+    }
+
+    fn side() -> impl Strategy<Value = OrderType> {
+        prop_oneof![Just(OrderType::Buy), Just(OrderType::Sell)]
+    }
+
+    fn action() -> impl Strategy<Value = Action> {
+        let time_in_force = prop_oneof![
+            4 => Just(TimeInForce::GoodTillCancel),
+            1 => Just(TimeInForce::ImmediateOrCancel),
+            1 => Just(TimeInForce::FillOrKill),
+        ];
+        let post_only = prop_oneof![
+            4 => Just(None),
+            1 => Just(Some(PostOnly::Reject)),
+            1 => Just(Some(PostOnly::Reprice)),
+        ];
+        let owner = prop_oneof![Just(None), Just(Some("alice")), Just(Some("bob"))];
+        let ticks = || 90..110i64;
+        prop_oneof![
+            8 => (side(), 1..20u32, ticks(), time_in_force, post_only, owner).prop_map(
+                |(side, quantity, ticks, time_in_force, post_only, owner)| Action::Place {
+                    side,
+                    quantity,
+                    ticks,
+                    time_in_force,
+                    post_only,
+                    owner,
+                }
+            ),
+            1 => (side(), 1..40u32).prop_map(|(side, quantity)| Action::Market { side, quantity }),
+            1 => (side(), 1..20u32, ticks(), prop::option::of(ticks())).prop_map(
+                |(side, quantity, trigger_ticks, limit_ticks)| Action::Stop {
+                    side,
+                    quantity,
+                    trigger_ticks,
+                    limit_ticks,
+                }
+            ),
+            1 => (side(), 1..40u32, 1..10u32, ticks()).prop_map(
+                |(side, quantity, display_quantity, ticks)| Action::Iceberg {
+                    side,
+                    quantity,
+                    display_quantity,
+                    ticks,
+                }
+            ),
+            2 => (any::<usize>(), 1..20u32, ticks()).prop_map(|(order, quantity, ticks)| {
+                Action::Update {
+                    order,
+                    quantity,
+                    ticks,
+                }
+            }),
+            2 => (any::<usize>(), prop::option::of(side()))
+                .prop_map(|(order, side)| Action::Cancel { order, side }),
+            1 => Just(Action::CancelAll),
+        ]
+    }
+
+    fn self_trade_prevention() -> impl Strategy<Value = SelfTradePrevention> {
+        prop_oneof![
+            Just(SelfTradePrevention::CancelNewest),
+            Just(SelfTradePrevention::CancelOldest),
+            Just(SelfTradePrevention::CancelBoth),
+            Just(SelfTradePrevention::DecrementAndCancel),
+        ]
+    }
+
+    fn command(action: &Action, placed: &[Uuid]) -> Option<Command> {
+        let price = |ticks| Decimal::new(ticks, 1);
+        let placed_order =
+            |order: usize| (!placed.is_empty()).then(|| placed[order % placed.len()]);
+        let command = match *action {
+            Action::Place {
+                side,
+                quantity,
+                ticks,
+                time_in_force,
+                post_only,
+                owner,
+            } => {
+                let owner = owner.map(str::to_owned);
+                match side {
+                    OrderType::Buy => Command::Buy {
+                        quantity,
+                        price: price(ticks),
+                        time_in_force,
+                        post_only,
+                        owner,
+                    },
+                    OrderType::Sell => Command::Sell {
+                        quantity,
+                        price: price(ticks),
+                        time_in_force,
+                        post_only,
+                        owner,
+                    },
+                }
+            }
+            Action::Market { side, quantity } => match side {
+                OrderType::Buy => Command::MarketBuy { quantity },
+                OrderType::Sell => Command::MarketSell { quantity },
+            },
+            Action::Stop {
+                side,
+                quantity,
+                trigger_ticks,
+                limit_ticks,
+            } => {
+                let trigger_price = price(trigger_ticks);
+                let limit_price = limit_ticks.map(price);
+                let time_in_force = TimeInForce::GoodTillCancel;
+                match side {
+                    OrderType::Buy => Command::BuyStop {
+                        quantity,
+                        trigger_price,
+                        limit_price,
+                        time_in_force,
+                    },
+                    OrderType::Sell => Command::SellStop {
+                        quantity,
+                        trigger_price,
+                        limit_price,
+                        time_in_force,
+                    },
+                }
+            }
+            Action::Iceberg {
+                side,
+                quantity,
+                display_quantity,
+                ticks,
+            } => {
+                let time_in_force = TimeInForce::GoodTillCancel;
+                match side {
+                    OrderType::Buy => Command::BuyIceberg {
+                        quantity,
+                        display_quantity,
+                        price: price(ticks),
+                        time_in_force,
+                    },
+                    OrderType::Sell => Command::SellIceberg {
+                        quantity,
+                        display_quantity,
+                        price: price(ticks),
+                        time_in_force,
+                    },
+                }
+            }
+            Action::Update {
+                order,
+                quantity,
+                ticks,
+            } => Command::Update {
+                id: placed_order(order)?,
+                side: None,
+                new_quantity: quantity,
+                new_price: price(ticks),
+            },
+            Action::Cancel { order, side } => Command::Cancel {
+                id: placed_order(order)?,
+                side,
+            },
+            Action::CancelAll => Command::CancelAll,
+        };
+        Some(command)
+    }
+
+    proptest! {
+        #[test]
+        fn test_invariants_hold_after_every_command(
+            self_trade_prevention in self_trade_prevention(),
+            actions in prop::collection::vec(action(), 1..150),
+        ) {
+            let mut order_book = OrderBook::new("test")
+                .with_self_trade_prevention(self_trade_prevention)
+                .with_invariant_checks(true);
+            let mut ledger = QuantityLedger::default();
+            let mut placed = vec![];
+            for action in &actions {
+                let Some(command) = command(action, &placed) else {
+                    continue;
+                };
+                let events = order_book.process(command);
+                placed.extend(events.iter().filter_map(|event| match event {
+                    Event::Accepted { order, .. } => Some(order.id),
+                    _ => None,
+                }));
+                ledger.record(&events);
+                prop_assert_eq!(verify(&order_book), Ok(()));
+                prop_assert_eq!(verify_fills(&events), Ok(()));
+                prop_assert_eq!(ledger.verify(&order_book), Ok(()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_violations_are_found() {
+        let ts = chrono::Utc::now();
+        let mut order_book = OrderBook::new("test");
+        order_book.insert(Order::buy(Uuid::new_v4(), ts, 5, Decimal::new(2, 0)));
+        order_book.insert(Order::sell(Uuid::new_v4(), ts, 5, Decimal::new(1, 0)));
+        assert!(verify(&order_book).is_err());
+
+        let mut order_book = OrderBook::new("test");
+        order_book.insert(Order::buy(Uuid::new_v4(), ts, 5, Decimal::new(2, 0)));
+        order_book.buy_index.clear();
+        assert!(verify(&order_book).is_err());
+
+        let mut order_book = OrderBook::new("test");
+        let order = Order::buy(Uuid::new_v4(), ts, 5, Decimal::new(2, 0));
+        order_book.insert(order.clone());
+        let ledger = QuantityLedger::default();
+        assert!(ledger.verify(&order_book).is_err());
+        let mut ledger = QuantityLedger::default();
+        ledger.record(&[Event::Accepted {
+            ts: order.ts,
+            order: Order {
+                quantity: 6,
+                ..order
+            },
+        }]);
+        assert!(ledger.verify(&order_book).is_err());
+    }
+}
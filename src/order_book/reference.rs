@@ -0,0 +1,297 @@
+//! A naive matcher the order book is checked against: resting orders are kept
+//! in arrival order and every match scans all of them for the best price.
+
+use rust_decimal::Decimal;
+use uuid::Uuid;
+
+use super::{
+    ids::{IdGenerator, SequentialIds},
+    Order, OrderBookState, OrderKind, OrderType, TimeInForce, Trade,
+};
+
+/// Hands out ids the way an order book with [`SequentialIds`] does, one for
+/// every order placed then one for every trade it makes.
+#[derive(Debug, Default)]
+pub(super) struct ReferenceBook {
+    orders: Vec<Order>,
+    last_price: Option<Decimal>,
+    ids: SequentialIds,
+}
+
+impl ReferenceBook {
+    /// The id of the next order to place.
+    pub(super) fn next_id(&mut self) -> Uuid {
+        self.ids.next_id()
+    }
+
+    /// Matches a limit or market order against the resting orders, resting
+    /// what is left of it when it may rest. A fill or kill order which cannot
+    /// be fully filled is killed before trading, then there are no trades.
+    pub(super) fn place(&mut self, mut order: Order) -> Option<Vec<Trade>> {
+        if order.time_in_force == TimeInForce::FillOrKill {
+            let available: u64 = self
+                .orders
+                .iter()
+                .filter(|counterpart| ReferenceBook::crosses(&order, counterpart))
+                .map(|counterpart| counterpart.quantity as u64)
+                .sum();
+            if available < order.quantity as u64 {
+                return None;
+            }
+        }
+        let mut trades = vec![];
+        while order.quantity > 0 {
+            let Some(index) = self.best_counterpart(&order) else {
+                break;
+            };
+            let maker = &mut self.orders[index];
+            let quantity = order.quantity.min(maker.quantity);
+            order.quantity -= quantity;
+            maker.quantity -= quantity;
+            trades.push(Trade {
+                id: self.ids.next_id(),
+                ts: order.ts,
+                price: maker.price,
+                quantity,
+                maker_order_id: maker.id,
+                taker_order_id: order.id,
+                aggressor_side: order.order_type,
+                maker_remaining_quantity: maker.quantity,
+                taker_remaining_quantity: order.quantity,
+            });
+            self.last_price = Some(maker.price);
+            if maker.quantity == 0 {
+                self.orders.remove(index);
+            }
+        }
+        if order.quantity > 0 && order.kind == OrderKind::Limit && order.time_in_force.rests() {
+            self.orders.push(order);
+        }
+        Some(trades)
+    }
+
+    pub(super) fn cancel(&mut self, id: Uuid) {
+        self.orders.retain(|order| order.id != id);
+    }
+
+    /// The resting orders, best price first then in arrival order.
+    pub(super) fn state(&self) -> OrderBookState {
+        let side = |order_type: OrderType| {
+            let mut orders: Vec<Order> = self
+                .orders
+                .iter()
+                .filter(|order| order.order_type == order_type)
+                .cloned()
+                .collect();
+            match order_type {
+                OrderType::Buy => orders.sort_by_key(|order| std::cmp::Reverse(order.price)),
+                OrderType::Sell => orders.sort_by_key(|order| order.price),
+            }
+            orders
+        };
+        OrderBookState {
+            buy: side(OrderType::Buy),
+            sell: side(OrderType::Sell),
+            stop: vec![],
+            last_price: self.last_price,
+        }
+    }
+
+    /// The earliest resting order of the other side at the best price the
+    /// order is willing to trade at.
+    fn best_counterpart(&self, order: &Order) -> Option<usize> {
+        let mut best: Option<usize> = None;
+        for (index, counterpart) in self.orders.iter().enumerate() {
+            if !ReferenceBook::crosses(order, counterpart) {
+                continue;
+            }
+            let better = match best.map(|best| &self.orders[best]) {
+                None => true,
+                Some(best) => match order.order_type {
+                    OrderType::Buy => counterpart.price < best.price,
+                    OrderType::Sell => counterpart.price > best.price,
+                },
+            };
+            if better {
+                best = Some(index);
+            }
+        }
+        best
+    }
+
+    fn crosses(order: &Order, counterpart: &Order) -> bool {
+        match (order.order_type, counterpart.order_type) {
+            (OrderType::Buy, OrderType::Sell) => {
+                order.kind == OrderKind::Market || counterpart.price <= order.price
+            }
+            (OrderType::Sell, OrderType::Buy) => {
+                order.kind == OrderKind::Market || counterpart.price >= order.price
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use chrono::{DateTime, Duration, TimeZone, Utc};
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::order_book::{
+        clock::{Clock, ManualClock},
+        Command, Event, OrderBook,
+    };
+
+    #[derive(Debug, Clone)]
+    enum Action {
+        Limit {
+            side: OrderType,
+            quantity: u32,
+            ticks: i64,
+            time_in_force: TimeInForce,
+        },
+        Market {
+            side: OrderType,
+            quantity: u32,
+        },
+        /// Cancels one of the orders placed so far, if any.
+        Cancel(usize),
+    }
+
+    fn side() -> impl Strategy<Value = OrderType> {
+        prop_oneof![Just(OrderType::Buy), Just(OrderType::Sell)]
+    }
+
+    fn action() -> impl Strategy<Value = Action> {
+        let time_in_force = prop_oneof![
+            3 => Just(TimeInForce::GoodTillCancel),
+            1 => Just(TimeInForce::ImmediateOrCancel),
+            1 => Just(TimeInForce::FillOrKill),
+        ];
+        prop_oneof![
+            6 => (side(), 1..20u32, 90..110i64, time_in_force).prop_map(
+                |(side, quantity, ticks, time_in_force)| Action::Limit {
+                    side,
+                    quantity,
+                    ticks,
+                    time_in_force,
+                }
+            ),
+            1 => (side(), 1..40u32).prop_map(|(side, quantity)| Action::Market { side, quantity }),
+            2 => any::<usize>().prop_map(Action::Cancel),
+        ]
+    }
+
+    fn command(action: &Action, placed: &[Uuid]) -> Option<Command> {
+        let command = match *action {
+            Action::Limit {
+                side,
+                quantity,
+                ticks,
+                time_in_force,
+            } => {
+                let price = Decimal::new(ticks, 1);
+                match side {
+                    OrderType::Buy => Command::Buy {
+                        quantity,
+                        price,
+                        time_in_force,
+                        post_only: None,
+                        owner: None,
+                    },
+                    OrderType::Sell => Command::Sell {
+                        quantity,
+                        price,
+                        time_in_force,
+                        post_only: None,
+                        owner: None,
+                    },
+                }
+            }
+            Action::Market { side, quantity } => match side {
+                OrderType::Buy => Command::MarketBuy { quantity },
+                OrderType::Sell => Command::MarketSell { quantity },
+            },
+            Action::Cancel(index) if !placed.is_empty() => Command::Cancel {
+                id: placed[index % placed.len()],
+                side: None,
+            },
+            Action::Cancel(_) => return None,
+        };
+        Some(command)
+    }
+
+    /// The order the reference places for an action, built from the action
+    /// alone and never from what the order book made of it.
+    fn order(action: &Action, id: Uuid, ts: DateTime<Utc>) -> Order {
+        match *action {
+            Action::Limit {
+                side,
+                quantity,
+                ticks,
+                time_in_force,
+            } => {
+                let price = Decimal::new(ticks, 1);
+                let order = match side {
+                    OrderType::Buy => Order::buy(id, ts, quantity, price),
+                    OrderType::Sell => Order::sell(id, ts, quantity, price),
+                };
+                Order {
+                    time_in_force,
+                    ..order
+                }
+            }
+            Action::Market { side, quantity } => match side {
+                OrderType::Buy => Order::market_buy(id, ts, quantity),
+                OrderType::Sell => Order::market_sell(id, ts, quantity),
+            },
+            Action::Cancel(_) => unreachable!("Cancels place no order"),
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_matching_agrees_with_reference(actions in prop::collection::vec(action(), 1..120)) {
+            let start = Utc.with_ymd_and_hms(2023, 6, 1, 12, 0, 0).unwrap();
+            let clock = Rc::new(ManualClock::new(start));
+            let mut order_book = OrderBook::new("test")
+                .with_clock(clock.clone())
+                .with_ids(SequentialIds::default());
+            let mut reference = ReferenceBook::default();
+            let mut placed = vec![];
+            for action in &actions {
+                clock.advance(Duration::seconds(1));
+                let Some(command) = command(action, &placed) else {
+                    continue;
+                };
+                let events = order_book.process(command);
+                let trades: Vec<Trade> = events
+                    .iter()
+                    .filter_map(|event| match event {
+                        Event::Filled { trade, .. } => Some(trade.clone()),
+                        _ => None,
+                    })
+                    .collect();
+                let expected = match action {
+                    Action::Cancel(index) => {
+                        reference.cancel(placed[index % placed.len()]);
+                        Some(vec![])
+                    }
+                    _ => {
+                        let id = reference.next_id();
+                        placed.push(id);
+                        reference.place(order(action, id, clock.now()))
+                    }
+                };
+                let killed = matches!(events.first(), Some(Event::Rejected { .. }))
+                    && !matches!(action, Action::Cancel(_));
+                prop_assert_eq!(killed, expected.is_none(), "events={:?}", events);
+                prop_assert_eq!(trades, expected.unwrap_or_default());
+                prop_assert_eq!(order_book.state(), reference.state());
+            }
+        }
+    }
+}
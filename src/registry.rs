@@ -0,0 +1,484 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+};
+
+use chrono::NaiveTime;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use tokio::{
+    sync::{mpsc, oneshot, Mutex},
+    task::{self, LocalSet},
+};
+
+use crate::{
+    actor::{self, Client, SequencedEvent, SnapshotPolicy},
+    database,
+    order_book::{InstrumentRules, SelfTradePrevention},
+    Error, Result,
+};
+
+/// The definition of a traded instrument. Prices are multiples of the tick
+/// size and orders follow the instrument rules.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Market {
+    pub ticker: String,
+    pub tick_size: Decimal,
+    #[serde(flatten)]
+    pub rules: InstrumentRules,
+}
+
+impl Market {
+    /// A market of any quantity, at any price.
+    pub fn new(ticker: &str, tick_size: Decimal) -> Self {
+        Market {
+            ticker: ticker.to_owned(),
+            tick_size,
+            rules: InstrumentRules::default(),
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        let reject = |reason: &str| Err(Error::invalid_market(reason));
+        let valid_ticker = self
+            .ticker
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if self.ticker.is_empty() || !valid_ticker {
+            return reject("Ticker must be made of letters, digits, '-' or '_'");
+        }
+        if self.tick_size <= Decimal::ZERO {
+            return reject("Tick size must be positive");
+        }
+        let rules = &self.rules;
+        if rules.lot_size == 0 {
+            return reject("Lot size must be positive");
+        }
+        if let (Some(min_quantity), Some(max_quantity)) = (rules.min_quantity, rules.max_quantity) {
+            if min_quantity > max_quantity {
+                return reject("Min quantity must not be above max quantity");
+            }
+        }
+        if rules
+            .min_notional
+            .is_some_and(|notional| notional <= Decimal::ZERO)
+        {
+            return reject("Min notional must be positive");
+        }
+        if rules.min_price.is_some_and(|price| price <= Decimal::ZERO) {
+            return reject("Min price must be positive");
+        }
+        if let (Some(min_price), Some(max_price)) = (rules.min_price, rules.max_price) {
+            if min_price > max_price {
+                return reject("Min price must not be above max price");
+            }
+        }
+        if rules.price_band.is_some_and(|band| band <= Decimal::ZERO) {
+            return reject("Price band must be positive");
+        }
+        Ok(())
+    }
+}
+
+/// The markets listed, by ticker, each with the client of the actor managing
+/// its order book.
+#[derive(Clone)]
+pub struct Registry {
+    db: sqlx::Pool<sqlx::Sqlite>,
+    markets: Arc<RwLock<BTreeMap<String, (Market, Client)>>>,
+    launcher: mpsc::Sender<Launch>,
+    /// Listings and delistings are done one at a time.
+    admin: Arc<Mutex<()>>,
+}
+
+impl Registry {
+    pub fn get(&self, ticker: &str) -> Result<Client> {
+        self.markets
+            .read()
+            .expect("Registry lock poisoned")
+            .get(ticker)
+            .map(|(_, client)| client.clone())
+            .ok_or_else(|| Error::MarketNotFound {
+                ticker: ticker.to_owned(),
+            })
+    }
+
+    /// The markets listed, in alphabetical order.
+    pub fn markets(&self) -> Vec<Market> {
+        self.markets
+            .read()
+            .expect("Registry lock poisoned")
+            .values()
+            .map(|(market, _)| market.clone())
+            .collect()
+    }
+
+    /// Starts the markets listed before, the `initial` markets are listed
+    /// first unless they were ever listed.
+    pub async fn restore(&self, initial: impl IntoIterator<Item = Market>) -> Result<()> {
+        let _admin = self.admin.lock().await;
+        for market in initial {
+            market.validate()?;
+            if database::save_market(&self.db, &market, false).await? {
+                tracing::info!("Market {} listed", market.ticker);
+            }
+        }
+        for market in database::load_markets(&self.db).await? {
+            self.start(market).await?;
+        }
+        Ok(())
+    }
+
+    /// Lists a new market, or lists again a delisted one.
+    pub async fn list(&self, market: Market) -> Result<Market> {
+        market.validate()?;
+        let _admin = self.admin.lock().await;
+        if self.get(&market.ticker).is_ok() {
+            return Err(Error::MarketAlreadyListed {
+                ticker: market.ticker,
+            });
+        }
+        database::save_market(&self.db, &market, true).await?;
+        self.start(market.clone()).await?;
+        tracing::info!("Market {} listed", market.ticker);
+        Ok(market)
+    }
+
+    /// Delists a market, canceling all of its orders and stopping its actor.
+    /// It stays listed until both are done.
+    pub async fn delist(&self, ticker: &str) -> Result<Vec<SequencedEvent>> {
+        let _admin = self.admin.lock().await;
+        let client = self.get(ticker)?;
+        let events = client.delist().await?;
+        database::delist_market(&self.db, ticker).await?;
+        self.markets
+            .write()
+            .expect("Registry lock poisoned")
+            .remove(ticker);
+        tracing::info!("Market {} delisted", ticker);
+        Ok(events)
+    }
+
+    async fn start(&self, market: Market) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        let launch = Launch {
+            market: market.clone(),
+            callback: sender,
+        };
+        let launcher_error = |error| {
+            tracing::error!("Fail to launch market {}, error={}", market.ticker, error);
+            Error::application_error("Internal server error")
+        };
+        self.launcher
+            .send(launch)
+            .await
+            .map_err(|error| launcher_error(error.to_string()))?;
+        let client = receiver
+            .await
+            .map_err(|error| launcher_error(error.to_string()))??;
+        self.markets
+            .write()
+            .expect("Registry lock poisoned")
+            .insert(market.ticker.clone(), (market, client));
+        Ok(())
+    }
+}
+
+struct Launch {
+    market: Market,
+    callback: oneshot::Sender<Result<Client>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Settings {
+    channel_buffer: usize,
+    snapshot_policy: SnapshotPolicy,
+    session_end: NaiveTime,
+    self_trade_prevention: SelfTradePrevention,
+    check_invariants: bool,
+}
+
+/// Builds and runs the actors of the markets listed, until delisted. Each
+/// actor is a task of its own, a market failing or busy leaves the others
+/// running.
+pub struct Launcher {
+    receiver: mpsc::Receiver<Launch>,
+    db: sqlx::Pool<sqlx::Sqlite>,
+    settings: Settings,
+}
+
+impl Launcher {
+    pub async fn run(mut self) -> Result<()> {
+        let actors = LocalSet::new();
+        actors
+            .run_until(async {
+                while let Some(request) = self.receiver.recv().await {
+                    let actor = launch(self.db.clone(), self.settings, request);
+                    task::spawn_local(async move {
+                        if let Err(error) = actor.await {
+                            tracing::error!("Market actor failed, error={}", error);
+                        }
+                    });
+                }
+            })
+            .await;
+        actors.await;
+        Ok(())
+    }
+}
+
+async fn launch(db: sqlx::Pool<sqlx::Sqlite>, settings: Settings, request: Launch) -> Result<()> {
+    let Launch { market, callback } = request;
+    let result = actor::build(
+        db,
+        &market,
+        settings.channel_buffer,
+        settings.snapshot_policy,
+        settings.session_end,
+        settings.self_trade_prevention,
+        settings.check_invariants,
+    )
+    .await;
+    match result {
+        Ok((client, actor)) => {
+            if callback.send(Ok(client)).is_err() {
+                tracing::warn!("Sender dropped the client of market {}", market.ticker);
+            }
+            actor.run().await
+        }
+        Err(error) => {
+            let _ = callback.send(Err(error));
+            Ok(())
+        }
+    }
+}
+
+pub fn build(
+    db: sqlx::Pool<sqlx::Sqlite>,
+    channel_buffer: usize,
+    snapshot_policy: SnapshotPolicy,
+    session_end: NaiveTime,
+    self_trade_prevention: SelfTradePrevention,
+    check_invariants: bool,
+) -> (Registry, Launcher) {
+    let (sender, receiver) = mpsc::channel(channel_buffer);
+    let registry = Registry {
+        db: db.clone(),
+        markets: Arc::default(),
+        launcher: sender,
+        admin: Arc::default(),
+    };
+    let launcher = Launcher {
+        receiver,
+        db,
+        settings: Settings {
+            channel_buffer,
+            snapshot_policy,
+            session_end,
+            self_trade_prevention,
+            check_invariants,
+        },
+    };
+    (registry, launcher)
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    use super::*;
+    use crate::order_book::{Event, TimeInForce};
+
+    fn start(db: &sqlx::Pool<sqlx::Sqlite>) -> Registry {
+        let (registry, launcher) = build(
+            db.clone(),
+            8,
+            SnapshotPolicy::default(),
+            NaiveTime::MIN,
+            SelfTradePrevention::default(),
+            true,
+        );
+        tokio::task::spawn_local(launcher.run());
+        registry
+    }
+
+    #[tokio::test]
+    async fn test_invalid_initial_markets_are_not_listed() {
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let db = SqlitePoolOptions::new()
+                    .max_connections(1)
+                    .connect("sqlite::memory:")
+                    .await
+                    .unwrap();
+                database::run_migrations(&db).await.unwrap();
+                let registry = start(&db);
+                assert!(matches!(
+                    registry.restore([Market::new("vibranium", dec!(0))]).await,
+                    Err(Error::InvalidMarket { .. })
+                ));
+                assert!(registry.markets().is_empty());
+                assert!(database::load_markets(&db).await.unwrap().is_empty());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_a_failing_market_leaves_the_others_running() {
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let db = SqlitePoolOptions::new()
+                    .max_connections(1)
+                    .connect("sqlite::memory:")
+                    .await
+                    .unwrap();
+                database::run_migrations(&db).await.unwrap();
+                let registry = start(&db);
+                registry
+                    .restore([
+                        Market::new("adamantium", dec!(0.01)),
+                        Market::new("vibranium", dec!(0.01)),
+                    ])
+                    .await
+                    .unwrap();
+                sqlx::query(
+                    r#"CREATE TRIGGER fail_adamantium BEFORE INSERT ON orderbook_event
+                    WHEN NEW.ticker = 'adamantium'
+                    BEGIN SELECT RAISE(ABORT, 'disk full'); END"#,
+                )
+                .execute(&db)
+                .await
+                .unwrap();
+
+                let gtc = TimeInForce::GoodTillCancel;
+                let adamantium = registry.get("adamantium").unwrap();
+                assert!(adamantium.buy(5, dec!(2), gtc, None, None).await.is_err());
+                let vibranium = registry.get("vibranium").unwrap();
+                vibranium.buy(5, dec!(2), gtc, None, None).await.unwrap();
+                assert_eq!(vibranium.get_order_book().await.unwrap().buy.len(), 1);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_markets_failing_to_be_delisted_stay_listed() {
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let db = SqlitePoolOptions::new()
+                    .max_connections(1)
+                    .connect("sqlite::memory:")
+                    .await
+                    .unwrap();
+                database::run_migrations(&db).await.unwrap();
+                let registry = start(&db);
+                registry
+                    .restore([Market::new("vibranium", dec!(0.01))])
+                    .await
+                    .unwrap();
+                sqlx::query(
+                    r#"CREATE TRIGGER fail_delisting BEFORE UPDATE ON orderbook_market
+                    BEGIN SELECT RAISE(ABORT, 'disk full'); END"#,
+                )
+                .execute(&db)
+                .await
+                .unwrap();
+
+                assert!(registry.delist("vibranium").await.is_err());
+                assert!(registry.get("vibranium").is_ok());
+                assert_eq!(registry.markets().len(), 1);
+                assert_eq!(database::load_markets(&db).await.unwrap().len(), 1);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_list_and_delist_markets() {
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let db = SqlitePoolOptions::new()
+                    .max_connections(1)
+                    .connect("sqlite::memory:")
+                    .await
+                    .unwrap();
+                database::run_migrations(&db).await.unwrap();
+                let registry = start(&db);
+                registry
+                    .restore([Market::new("vibranium", dec!(0.01))])
+                    .await
+                    .unwrap();
+
+                let adamantium = Market {
+                    rules: InstrumentRules {
+                        lot_size: 10,
+                        min_quantity: Some(20),
+                        max_quantity: Some(1000),
+                        min_notional: Some(dec!(50)),
+                        min_price: Some(dec!(1)),
+                        max_price: Some(dec!(100)),
+                        price_band: Some(dec!(0.1)),
+                    },
+                    ..Market::new("adamantium", dec!(0.5))
+                };
+                registry.list(adamantium.clone()).await.unwrap();
+                assert!(registry.list(adamantium.clone()).await.is_err());
+                assert!(registry
+                    .list(Market::new("bad/ticker", dec!(0.01)))
+                    .await
+                    .is_err());
+                assert!(registry
+                    .list(Market::new("unobtainium", dec!(0)))
+                    .await
+                    .is_err());
+                let tickers: Vec<String> = registry
+                    .markets()
+                    .into_iter()
+                    .map(|market| market.ticker)
+                    .collect();
+                assert_eq!(tickers, vec!["adamantium", "vibranium"]);
+
+                let gtc = TimeInForce::GoodTillCancel;
+                let client = registry.get("vibranium").unwrap();
+                client.buy(5, dec!(2), gtc, None, None).await.unwrap();
+                client.sell(3, dec!(4), gtc, None, None).await.unwrap();
+                let events = registry.delist("vibranium").await.unwrap();
+                assert_eq!(events.len(), 2);
+                assert!(events
+                    .iter()
+                    .all(|event| matches!(event.event, Event::Canceled { .. })));
+                assert!(matches!(
+                    registry.get("vibranium"),
+                    Err(Error::MarketNotFound { .. })
+                ));
+                assert!(client.get_order_book().await.is_err());
+                // Delisted, its events are still there to be queried.
+                assert!(database::market_exists(&db, "vibranium").await.unwrap());
+                assert!(!database::market_exists(&db, "unobtainium").await.unwrap());
+                let query = database::EventQuery::default();
+                let page = database::query_events(&db, "vibranium", &query, 0, 10)
+                    .await
+                    .unwrap();
+                assert_eq!(page.events.len(), 4);
+
+                // Restarted, the delisted initial market is not listed again.
+                let registry = start(&db);
+                registry
+                    .restore([Market::new("vibranium", dec!(0.01))])
+                    .await
+                    .unwrap();
+                assert_eq!(registry.markets(), vec![adamantium]);
+
+                registry
+                    .list(Market::new("vibranium", dec!(0.05)))
+                    .await
+                    .unwrap();
+                let client = registry.get("vibranium").unwrap();
+                assert_eq!(client.get_order_book().await.unwrap().buy.len(), 0);
+            })
+            .await;
+    }
+}